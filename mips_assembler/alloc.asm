@@ -0,0 +1,234 @@
+; Heap allocator runtime, linked after the program with --runtime alloc
+;
+; The heap starts just past the end of the program's last section (leaving
+; room for the array passed in by the mipsarray driver) and runs up to
+; 0x10000 bytes below the stack pointer at the time of __alloc_init, the
+; stack size --sanitize checks by default. Every block starts with a header
+; word holding its size in words, header included. Free blocks keep the
+; address of the next free block in their second word, in address order so
+; neighbours can be merged.
+;
+; Every label starts with __alloc so none clash with the program's.
+;
+; All registers except $3 are preserved.
+
+; in:  $2 = number of words to skip after the program (array length or 0)
+__alloc_init:
+sw $2, -4($30)
+sw $3, -8($30)
+sw $4, -12($30)
+sw $5, -16($30)
+lis $4
+.word 16
+sub $30, $30, $4
+
+; $3 = heap start = __alloc_end + 8 + 4 * $2
+lis $4
+.word 4
+mult $2, $4
+mflo $3
+lis $4
+.word __alloc_end
+add $3, $3, $4
+lis $4
+.word 8
+add $3, $3, $4
+
+; One free block covering the whole heap, up to the stack reserve
+lis $4
+.word 0x10000
+sub $4, $30, $4
+lis $5
+.word 16
+add $4, $4, $5          ; $30 was lowered by 16 above
+sub $4, $4, $3
+lis $5
+.word 4
+divu $4, $5
+mflo $4                 ; $4 = heap size in words
+sw $4, 0($3)
+sw $0, 4($3)
+lis $4
+.word __alloc_free_list
+sw $3, 0($4)
+
+lis $4
+.word 16
+add $30, $30, $4
+lw $2, -4($30)
+lw $3, -8($30)
+lw $4, -12($30)
+lw $5, -16($30)
+jr $31
+
+; in:  $1 = number of words
+; out: $3 = address of the allocated words, or 0 if the heap is exhausted
+__alloc_new:
+sw $1, -4($30)
+sw $2, -8($30)
+sw $4, -12($30)
+sw $5, -16($30)
+sw $6, -20($30)
+sw $7, -24($30)
+lis $2
+.word 24
+sub $30, $30, $2
+
+; $1 = words needed including the header, at least 2 so the block can be freed
+lis $2
+.word 1
+add $1, $1, $2
+lis $2
+.word 2
+slt $4, $1, $2
+beq $4, $0, __alloc_new_search
+add $1, $2, $0
+
+__alloc_new_search:
+lis $4
+.word __alloc_free_list ; $4 = address of the link to the current block
+lw $5, 0($4)            ; $5 = current block
+
+__alloc_new_loop:
+beq $5, $0, __alloc_new_fail
+lw $6, 0($5)            ; $6 = size of current block
+slt $7, $6, $1
+beq $7, $0, __alloc_new_found
+lis $4
+.word 4
+add $4, $5, $4          ; link = &current.next
+lw $5, 0($4)
+beq $0, $0, __alloc_new_loop
+
+__alloc_new_found:
+sub $7, $6, $1          ; $7 = words left over
+lis $2
+.word 2
+slt $2, $7, $2
+bne $2, $0, __alloc_new_whole
+
+; Split off the tail as a new free block
+lis $2
+.word 4
+mult $1, $2
+mflo $2
+add $2, $5, $2          ; $2 = tail block
+sw $7, 0($2)
+lw $7, 4($5)
+sw $7, 4($2)
+sw $2, 0($4)
+sw $1, 0($5)
+beq $0, $0, __alloc_new_done
+
+__alloc_new_whole:
+lw $7, 4($5)
+sw $7, 0($4)
+
+__alloc_new_done:
+lis $3
+.word 4
+add $3, $5, $3
+beq $0, $0, __alloc_new_return
+
+__alloc_new_fail:
+add $3, $0, $0
+
+__alloc_new_return:
+lis $2
+.word 24
+add $30, $30, $2
+lw $1, -4($30)
+lw $2, -8($30)
+lw $4, -12($30)
+lw $5, -16($30)
+lw $6, -20($30)
+lw $7, -24($30)
+jr $31
+
+; in:  $1 = address returned by __alloc_new, or 0 to do nothing
+__alloc_delete:
+sw $1, -4($30)
+sw $2, -8($30)
+sw $3, -12($30)
+sw $4, -16($30)
+sw $5, -20($30)
+sw $6, -24($30)
+sw $7, -28($30)
+lis $2
+.word 28
+sub $30, $30, $2
+
+beq $1, $0, __alloc_delete_return
+lis $2
+.word 4
+sub $1, $1, $2          ; $1 = block
+
+; Find the free blocks on either side: $3 = previous (or 0), $5 = next
+add $3, $0, $0
+lis $4
+.word __alloc_free_list
+lw $5, 0($4)
+
+__alloc_delete_loop:
+beq $5, $0, __alloc_delete_insert
+slt $6, $1, $5
+bne $6, $0, __alloc_delete_insert
+add $3, $5, $0
+lis $4
+.word 4
+add $4, $5, $4
+lw $5, 0($4)
+beq $0, $0, __alloc_delete_loop
+
+__alloc_delete_insert:
+sw $5, 4($1)
+sw $1, 0($4)
+
+; Merge with the next block if they touch
+lw $6, 0($1)
+lis $2
+.word 4
+mult $6, $2
+mflo $7
+add $7, $1, $7
+bne $7, $5, __alloc_delete_merge_prev
+lw $7, 0($5)
+add $6, $6, $7
+sw $6, 0($1)
+lw $7, 4($5)
+sw $7, 4($1)
+
+; Merge with the previous block if they touch
+__alloc_delete_merge_prev:
+beq $3, $0, __alloc_delete_return
+lw $6, 0($3)
+mult $6, $2
+mflo $7
+add $7, $3, $7
+bne $7, $1, __alloc_delete_return
+lw $7, 0($1)
+add $6, $6, $7
+sw $6, 0($3)
+lw $7, 4($1)
+sw $7, 4($3)
+
+__alloc_delete_return:
+lis $2
+.word 28
+add $30, $30, $2
+lw $1, -4($30)
+lw $2, -8($30)
+lw $3, -12($30)
+lw $4, -16($30)
+lw $5, -20($30)
+lw $6, -24($30)
+lw $7, -28($30)
+jr $31
+
+.data
+__alloc_free_list:
+.word 0
+
+; Laid out after every other section, so the heap starts past all of them
+.bss
+__alloc_end:
@@ -0,0 +1,49 @@
+; Computes fib(24) recursively into $3, keeping everything else on the stack
+lis $1
+.word 24
+lis $4
+.word fibRec
+sw $31, -4($30)
+lis $5
+.word 4
+sub $30, $30, $5
+jalr $4
+add $30, $30, $5
+lw $31, -4($30)
+jr $31
+
+; in: $1 = n, out: $3 = fib(n); all other registers are preserved
+fibRec:
+sw $31, -4($30)
+sw $1, -8($30)
+sw $2, -12($30)
+sw $5, -16($30)
+lis $5
+.word 16
+sub $30, $30, $5
+lis $5
+.word 2
+slt $2, $1, $5
+beq $2, $0, fibSplit
+add $3, $1, $0
+beq $0, $0, fibReturn
+
+fibSplit:
+lis $5
+.word 1
+sub $1, $1, $5
+jalr $4
+add $2, $3, $0
+sub $1, $1, $5
+jalr $4
+add $3, $3, $2
+
+fibReturn:
+lis $5
+.word 16
+add $30, $30, $5
+lw $31, -4($30)
+lw $1, -8($30)
+lw $2, -12($30)
+lw $5, -16($30)
+jr $31
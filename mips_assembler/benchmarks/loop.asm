@@ -0,0 +1,11 @@
+; Sums the integers 1..3000000 into $3, wrapping on overflow
+lis $4
+.word 3000000
+lis $5
+.word 1
+add $3, $0, $0
+sumLoop:
+add $3, $3, $4
+sub $4, $4, $5
+bne $4, $0, sumLoop
+jr $31
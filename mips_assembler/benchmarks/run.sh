@@ -0,0 +1,9 @@
+#!/bin/sh
+# Times every benchmark under each execution engine, failing if their final
+# machine states differ
+cd "$(dirname "$0")/.." || exit 1
+cargo build --release --quiet || exit 1
+for program in benchmarks/*.asm; do
+    echo "== $program"
+    ./target/release/mips_assembler --bench "$program" || exit 1
+done
@@ -0,0 +1,52 @@
+; Fills an array with 500 pseudo-random words and bubble sorts it in place
+; $3 = smallest element afterwards
+lis $10
+.word array
+lis $11
+.word 500               ; $11 = length
+lis $12
+.word 4
+lis $13
+.word 1
+lis $14
+.word 1103515245
+lis $15
+.word 12345
+add $6, $0, $0          ; $6 = seed
+add $7, $10, $0         ; $7 = cursor
+add $8, $11, $0         ; $8 = words left to fill
+fill:
+mult $6, $14
+mflo $6
+add $6, $6, $15
+sw $6, 0($7)
+add $7, $7, $12
+sub $8, $8, $13
+bne $8, $0, fill
+
+; for (n = length - 1; n != 0; n--) for (i = 0; i != n; i++) order a[i], a[i+1]
+sub $8, $11, $13        ; $8 = n
+outer:
+beq $8, $0, sorted
+add $7, $10, $0         ; $7 = &a[i]
+add $9, $8, $0          ; $9 = comparisons left in this pass
+inner:
+lw $4, 0($7)
+lw $5, 4($7)
+slt $16, $5, $4
+beq $16, $0, noSwap
+sw $5, 0($7)
+sw $4, 4($7)
+noSwap:
+add $7, $7, $12
+sub $9, $9, $13
+bne $9, $0, inner
+sub $8, $8, $13
+beq $0, $0, outer
+
+sorted:
+lw $3, 0($10)
+jr $31
+
+array:
+.word 0
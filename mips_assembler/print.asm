@@ -1,85 +1,92 @@
 ; Takes a twos complement integer in register $1 and prints it out to stdout
 ; storing to 0xffff000c prints to stdout
 ; loading from 0xffff0004 grabs from stdin
+;
+; Assembling with -D RELEASE leaves out saving and restoring registers, so
+; the caller has to treat every register used here as clobbered.
 
-; Setup constants
-; $8 = stdout
-; $9 = - = 45
-; $10 = 0 = 48
-; $11 = 10
-; $12 = 4
+.reg num, $1
+.reg tmp, $2
+.reg stdout, $8
+.reg minus, $9          ; '-' = 45
+.reg digit0, $10        ; '0' = 48
+.reg ten, $11
+.reg four, $12
 
 print:
 preamble:
+.ifndef RELEASE
 ; Save registers onto stack
-sw $1, -4($30)
-sw $2, -8($30)
+sw $num, -4($30)
+sw $tmp, -8($30)
 sw $3, -12($30)
-sw $8, -16($30)
-sw $9, -20($30)
-sw $10, -24($30)
-sw $11, -28($30)
-sw $12, -32($30)
+sw $stdout, -16($30)
+sw $minus, -20($30)
+sw $digit0, -24($30)
+sw $ten, -28($30)
+sw $four, -32($30)
 sw $29, -36($30)
-lis $2
+lis $tmp
 .word 36
-sub $30, $30, $2
+sub $30, $30, $tmp
+.endif
 
-constants:
-lis $8
+lis $stdout
 .word 0xffff000c
-lis $9
+lis $minus
 .word 45
-lis $10
+lis $digit0
 .word 48
-lis $11
+lis $ten
 .word 10
-lis $12
+lis $four
 .word 4
 
 ; $29 = old SP
-add $29, $30, $0
+add $29, $30, $zero
 
-bne $1, $0, nonzero
+bne $num, $zero, nonzero
 ; Zero case
-sw $10, 0($8)           ; print 0
-beq $0, $0, postamble
+sw $digit0, 0($stdout)  ; print 0
+beq $zero, $zero, postamble
 
 nonzero:
-slt $2, $1, $0          ; $2 is 1 iff num is negative
-beq $2, $0, positive    ; If $2 == 1, then print a minus sign and negate $1
-sw $9, 0($8)            ; print -
-sub $1, $0, $1          ; negate $1
+slt $tmp, $num, $zero   ; $tmp is 1 iff num is negative
+beq $tmp, $zero, positive ; If $tmp == 1, then print a minus sign and negate $num
+sw $minus, 0($stdout)   ; print -
+sub $num, $zero, $num   ; negate $num
 
 positive:
-beq $1, $0, printStack  ; if num == 0, done
-divu $1, $11            ; lo = num / 10, hi = num % 10
-mfhi $1                 ; $1 = remainder
-add $1, $1, $10         ; $1 now has the character
-sub $30, $30, $12       ; SP -= 4
-sw $1, 0($30)           ; push character onto stack
-mflo $1                 ; $1 = quotient
-beq $0, $0, positive    ; loop
+beq $num, $zero, printStack ; if num == 0, done
+divu $num, $ten         ; lo = num / 10, hi = num % 10
+mfhi $num               ; $num = remainder
+add $num, $num, $digit0 ; $num now has the character
+sub $30, $30, $four     ; SP -= 4
+sw $num, 0($30)         ; push character onto stack
+mflo $num               ; $num = quotient
+beq $zero, $zero, positive ; loop
 
 printStack:
 beq $29, $30, postamble
-lw $1, 0($30)           ; grab character from stack
-add $30, $30, $12       ; SP += 4
-sw $1, 0($8)            ; print character
-beq $0, $0, printStack  ; loop
+lw $num, 0($30)         ; grab character from stack
+add $30, $30, $four     ; SP += 4
+sw $num, 0($stdout)     ; print character
+beq $zero, $zero, printStack ; loop
 
 postamble:
+.ifndef RELEASE
 ; Grab saved registers from stack and jump back
-lis $2
+lis $tmp
 .word 36
-add $30, $30, $2
-lw $1, -4($30)
-lw $2, -8($30)
+add $30, $30, $tmp
+lw $num, -4($30)
+lw $tmp, -8($30)
 lw $3, -12($30)
-lw $8, -16($30)
-lw $9, -20($30)
-lw $10, -24($30)
-lw $11, -28($30)
-lw $12, -32($30)
+lw $stdout, -16($30)
+lw $minus, -20($30)
+lw $digit0, -24($30)
+lw $ten, -28($30)
+lw $four, -32($30)
 lw $29, -36($30)
-jr $31
+.endif
+jr $ra
@@ -0,0 +1,44 @@
+use std::time::{Duration, Instant};
+
+use crate::differential;
+use crate::predecode::Engine;
+use crate::{MipsEmulator, Program};
+
+fn timed_run(program: &Program, engine: Engine) -> (MipsEmulator, Duration) {
+    let mut emulator = MipsEmulator::new(program);
+    emulator.engine = engine;
+    let start = Instant::now();
+    emulator.run();
+    (emulator, start.elapsed())
+}
+
+fn rate(steps: u64, elapsed: Duration) -> String {
+    let per_second = steps as f64 / elapsed.as_secs_f64().max(1e-9);
+    format!(
+        "{steps} instructions in {:.3}s, {:.2} million instructions/s",
+        elapsed.as_secs_f64(),
+        per_second / 1e6
+    )
+}
+
+// Runs the program under every engine, reporting the speed of each relative
+// to the reference interpreter. Returns false if any of them disagree.
+// Benchmarks take no input, so $1 and $2 start out as zero.
+pub fn run(program: &Program) -> bool {
+    let (reference, reference_time) = timed_run(program, Engine::Reference);
+    eprintln!("reference:  {}", rate(reference.steps, reference_time));
+    let mut identical = true;
+    for (name, engine) in [("predecode:", Engine::Predecode), ("jit:", Engine::Jit)] {
+        let (emulator, time) = timed_run(program, engine);
+        eprintln!(
+            "{name:11} {}, {:.2}x",
+            rate(emulator.steps, time),
+            reference_time.as_secs_f64() / time.as_secs_f64().max(1e-9)
+        );
+        for difference in differential::compare(&reference, &emulator) {
+            eprintln!("mismatch: {difference}");
+            identical = false;
+        }
+    }
+    identical
+}
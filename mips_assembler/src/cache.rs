@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    // Write-back caches allocate on a write miss; write-through caches don't
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub size: u32,
+    pub associativity: u32,
+    pub line_size: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+}
+
+impl CacheConfig {
+    // size:associativity:line_size[:lru|fifo|random[:wb|wt]], sizes in bytes
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() < 3 || parts.len() > 5 {
+            return Err(format!(
+                "Expected size:assoc:line[:policy[:write]], got {spec}"
+            ));
+        }
+        let number = |part: &str| {
+            part.parse::<u32>()
+                .map_err(|_| format!("Invalid number {part} in cache specification"))
+        };
+        let replacement = match parts.get(3) {
+            None | Some(&"lru") => Replacement::Lru,
+            Some(&"fifo") => Replacement::Fifo,
+            Some(&"random") => Replacement::Random,
+            Some(other) => return Err(format!("Unknown replacement policy {other}")),
+        };
+        let write_policy = match parts.get(4) {
+            None | Some(&"wb") => WritePolicy::WriteBack,
+            Some(&"wt") => WritePolicy::WriteThrough,
+            Some(other) => return Err(format!("Unknown write policy {other}")),
+        };
+        let config = CacheConfig {
+            size: number(parts[0])?,
+            associativity: number(parts[1])?,
+            line_size: number(parts[2])?,
+            replacement,
+            write_policy,
+        };
+        if !config.line_size.is_power_of_two() || config.line_size < 4 {
+            return Err(String::from(
+                "Line size must be a power of two of at least 4",
+            ));
+        }
+        if !config.num_sets().is_some_and(u32::is_power_of_two) {
+            return Err(String::from(
+                "Cache size must be a power-of-two number of sets of assoc * line bytes",
+            ));
+        }
+        Ok(config)
+    }
+
+    // None unless the size is a whole number of sets of assoc * line bytes,
+    // including when that product doesn't fit in 32 bits
+    fn num_sets(&self) -> Option<u32> {
+        let way_size = self.associativity.checked_mul(self.line_size)?;
+        (way_size != 0 && self.size.is_multiple_of(way_size)).then(|| self.size / way_size)
+    }
+}
+
+impl fmt::Display for CacheConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let replacement = match self.replacement {
+            Replacement::Lru => "LRU",
+            Replacement::Fifo => "FIFO",
+            Replacement::Random => "random",
+        };
+        let write_policy = match self.write_policy {
+            WritePolicy::WriteBack => "write-back",
+            WritePolicy::WriteThrough => "write-through",
+        };
+        write!(
+            f,
+            "{} B, {}-way, {} B lines, {replacement}, {write_policy}",
+            self.size, self.associativity, self.line_size
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CacheLine {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    last_used: u64,
+    inserted: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct AccessCounts {
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    name: String,
+    config: CacheConfig,
+    sets: Vec<Vec<CacheLine>>,
+    clock: u64,
+    rng: u32,
+    counts: AccessCounts,
+    writebacks: u64,
+    memory_writes: u64,
+    per_pc: HashMap<u32, AccessCounts>,
+}
+
+impl Cache {
+    pub fn new(name: &str, config: CacheConfig) -> Cache {
+        let num_sets = config
+            .num_sets()
+            .expect("Cache configuration was not validated");
+        Cache {
+            name: name.to_string(),
+            sets: vec![
+                vec![CacheLine::default(); config.associativity as usize];
+                num_sets as usize
+            ],
+            config,
+            clock: 0,
+            rng: 0x2545f491,
+            counts: AccessCounts::default(),
+            writebacks: 0,
+            memory_writes: 0,
+            per_pc: HashMap::new(),
+        }
+    }
+
+    fn next_random(&mut self) -> u32 {
+        // xorshift32, so runs are reproducible
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    // Returns whether the access hit. `pc` is the instruction responsible.
+    pub fn access(&mut self, addr: u32, is_write: bool, pc: u32) -> bool {
+        self.clock += 1;
+        let line_number = addr / self.config.line_size;
+        let num_sets = self.sets.len() as u32;
+        let set_index = (line_number % num_sets) as usize;
+        let tag = line_number / num_sets;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        if is_write && !write_back {
+            self.memory_writes += 1;
+        }
+
+        let random = match self.config.replacement {
+            Replacement::Random => self.next_random() as usize,
+            _ => 0,
+        };
+        let set = &mut self.sets[set_index];
+        let hit = match set.iter_mut().find(|line| line.valid && line.tag == tag) {
+            Some(line) => {
+                line.last_used = self.clock;
+                line.dirty |= is_write && write_back;
+                true
+            }
+            None => {
+                if !is_write || write_back {
+                    let victim = match set.iter().position(|line| !line.valid) {
+                        Some(idx) => idx,
+                        None => match self.config.replacement {
+                            Replacement::Lru => (0..set.len())
+                                .min_by_key(|idx| set[*idx].last_used)
+                                .unwrap(),
+                            Replacement::Fifo => {
+                                (0..set.len()).min_by_key(|idx| set[*idx].inserted).unwrap()
+                            }
+                            Replacement::Random => random % set.len(),
+                        },
+                    };
+                    if set[victim].valid && set[victim].dirty {
+                        self.writebacks += 1;
+                    }
+                    set[victim] = CacheLine {
+                        valid: true,
+                        dirty: is_write,
+                        tag,
+                        last_used: self.clock,
+                        inserted: self.clock,
+                    };
+                }
+                false
+            }
+        };
+
+        let per_pc = self.per_pc.entry(pc).or_default();
+        if hit {
+            self.counts.hits += 1;
+            per_pc.hits += 1;
+        } else {
+            self.counts.misses += 1;
+            per_pc.misses += 1;
+        }
+        hit
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let rate = |counts: &AccessCounts| {
+            100.0 * counts.hits as f64 / (counts.hits + counts.misses).max(1) as f64
+        };
+        let mut result = String::new();
+        writeln!(result, "{} ({})", self.name, self.config).unwrap();
+        writeln!(
+            result,
+            "  {} accesses, {} hits, {} misses, {:.2}% hit rate",
+            self.counts.hits + self.counts.misses,
+            self.counts.hits,
+            self.counts.misses,
+            rate(&self.counts)
+        )
+        .unwrap();
+        match self.config.write_policy {
+            WritePolicy::WriteBack => {
+                writeln!(result, "  {} dirty lines written back", self.writebacks).unwrap()
+            }
+            WritePolicy::WriteThrough => {
+                writeln!(result, "  {} writes to memory", self.memory_writes).unwrap()
+            }
+        }
+
+        let mut per_label: HashMap<String, AccessCounts> = HashMap::new();
+        for (pc, counts) in &self.per_pc {
+            let label = match symbols.enclosing(*pc) {
+                Some((name, _)) => name.to_string(),
+                None => format!("0x{pc:08x}"),
+            };
+            let entry = per_label.entry(label).or_default();
+            entry.hits += counts.hits;
+            entry.misses += counts.misses;
+        }
+        let mut per_label: Vec<(String, AccessCounts)> = per_label.into_iter().collect();
+        per_label.sort_by(|a, b| b.1.misses.cmp(&a.1.misses).then_with(|| a.0.cmp(&b.0)));
+        writeln!(
+            result,
+            "  {:>12} {:>12} {:>9}  label",
+            "accesses", "misses", "hit rate"
+        )
+        .unwrap();
+        for (label, counts) in per_label {
+            writeln!(
+                result,
+                "  {:>12} {:>12} {:>8.2}%  {label}",
+                counts.hits + counts.misses,
+                counts.misses,
+                rate(&counts)
+            )
+            .unwrap();
+        }
+        result
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::symbols::SymbolTable;
+
+struct Frame {
+    callee: u32,
+    call_site: u32,
+    return_address: u32,
+    saved: [u32; 32],
+}
+
+// Snapshots the preserved registers on every jalr and compares them when the
+// callee returns with jr $31.
+pub struct CallChecker {
+    symbols: SymbolTable,
+    locations: HashMap<u32, String>,
+    preserved: Vec<u8>,
+    frames: Vec<Frame>,
+    last_writer: [Option<u32>; 32],
+    reported: HashSet<(u32, u8)>,
+    pub errors: u64,
+}
+
+// Parses a register list like "1,2,4-29"
+pub fn parse_register_list(list: &str) -> Result<Vec<u8>, String> {
+    let mut result = Vec::new();
+    for part in list.split(',') {
+        let part = part.trim().trim_start_matches('$');
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last.trim_start_matches('$')),
+            None => (part, part),
+        };
+        let parse = |reg: &str| match reg.parse::<u8>() {
+            Ok(reg) if reg < 32 => Ok(reg),
+            _ => Err(format!("Invalid register {reg}")),
+        };
+        result.extend(parse(first)?..=parse(last)?);
+    }
+    Ok(result)
+}
+
+impl CallChecker {
+    pub fn new(
+        symbols: SymbolTable,
+        locations: HashMap<u32, String>,
+        preserved: Vec<u8>,
+    ) -> CallChecker {
+        CallChecker {
+            symbols,
+            locations,
+            preserved,
+            frames: Vec::new(),
+            last_writer: [None; 32],
+            reported: HashSet::new(),
+            errors: 0,
+        }
+    }
+
+    fn location(&self, pc: u32) -> String {
+        match self.locations.get(&pc) {
+            Some(location) => format!("{location} ({})", self.symbols.describe(pc)),
+            None => self.symbols.describe(pc),
+        }
+    }
+
+    pub fn record_write(&mut self, reg: u8, pc: u32) {
+        self.last_writer[reg as usize] = Some(pc);
+    }
+
+    pub fn record_call(&mut self, target: u32, return_address: u32, registers: &[u32; 32]) {
+        self.frames.push(Frame {
+            callee: target,
+            call_site: return_address.wrapping_sub(4),
+            return_address,
+            saved: *registers,
+        });
+    }
+
+    pub fn record_return(&mut self, target: u32, registers: &[u32; 32]) {
+        let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == target)
+        else {
+            return;
+        };
+        let frame = self.frames.remove(depth);
+        self.frames.truncate(depth);
+        for reg in &self.preserved {
+            let (before, after) = (frame.saved[*reg as usize], registers[*reg as usize]);
+            if before == after {
+                continue;
+            }
+            self.errors += 1;
+            // Report each register once per function
+            if !self.reported.insert((frame.callee, *reg)) {
+                continue;
+            }
+            let clobbered_at = match self.last_writer[*reg as usize] {
+                Some(pc) => self.location(pc),
+                None => String::from("an unknown instruction"),
+            };
+            eprintln!(
+                "calling convention: {} clobbered ${reg} (0x{before:08x} -> 0x{after:08x}), \
+                 last written at {clobbered_at}, called from {}",
+                self.symbols.describe(frame.callee),
+                self.location(frame.call_site)
+            );
+        }
+    }
+}
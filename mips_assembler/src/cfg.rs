@@ -0,0 +1,666 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use crate::registers::RegisterNames;
+use crate::sections::Section;
+use crate::symbols::SymbolTable;
+use crate::{Instruction, Line, Value};
+
+// hi and lo are tracked alongside the 32 general purpose registers
+pub const HI: u8 = 32;
+pub const LO: u8 = 33;
+
+// One bit per register, indexed like the registers themselves
+pub type RegisterSet = u64;
+
+// Every register that can hold a value; $0 is always zero so it is never
+// live and never defined
+pub const ALL: RegisterSet = ((1 << 34) - 1) & !1;
+
+fn bit(reg: u8) -> RegisterSet {
+    match reg {
+        0 => 0,
+        reg => 1 << reg,
+    }
+}
+
+// Sets with most registers in them are listed by what they leave out
+pub fn format_registers(set: RegisterSet, names: RegisterNames) -> String {
+    let list = |set: RegisterSet| {
+        let list: Vec<String> = (1..=LO)
+            .filter(|reg| set & bit(*reg) != 0)
+            .map(|reg| match reg {
+                HI => String::from("hi"),
+                LO => String::from("lo"),
+                reg => names.name(reg),
+            })
+            .collect();
+        list.join(" ")
+    };
+    match (set & ALL).count_ones() {
+        count if count == ALL.count_ones() => String::from("all"),
+        count if 2 * count > ALL.count_ones() => format!("all but {}", list(ALL & !set)),
+        _ => list(set),
+    }
+}
+
+// An instruction in a basic block. A lis and the .word after it form a single
+// op with the loaded constant in `value`.
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub address: u32,
+    pub instruction: Instruction,
+    pub value: Option<u32>,
+}
+
+impl Op {
+    fn size(&self) -> u32 {
+        match self.value {
+            Some(_) => 8,
+            None => 4,
+        }
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(self.instruction, Instruction::Jalr { .. })
+    }
+
+    // Whether the op does nothing but compute its result. Loads are not,
+    // since they can read from devices.
+    pub fn is_pure(&self) -> bool {
+        matches!(
+            self.instruction,
+            Instruction::Add { .. }
+                | Instruction::Sub { .. }
+                | Instruction::Slt { .. }
+                | Instruction::Sltu { .. }
+                | Instruction::Mult { .. }
+                | Instruction::Multu { .. }
+                | Instruction::Mfhi { .. }
+                | Instruction::Mflo { .. }
+                | Instruction::Lis { .. }
+        )
+    }
+
+    // Registers read. Calls pass arguments in any register, so they read all
+    // of them.
+    pub fn uses(&self) -> RegisterSet {
+        if self.is_call() {
+            return ALL;
+        }
+        let mut set = self
+            .instruction
+            .sources()
+            .into_iter()
+            .fold(0, |set, reg| set | bit(reg));
+        match self.instruction {
+            Instruction::Mfhi { .. } => set |= bit(HI),
+            Instruction::Mflo { .. } => set |= bit(LO),
+            _ => {}
+        }
+        set
+    }
+
+    // Registers written. A call may change any register.
+    pub fn defs(&self) -> RegisterSet {
+        if self.is_call() {
+            return ALL;
+        }
+        match self.instruction {
+            Instruction::Mult { .. }
+            | Instruction::Multu { .. }
+            | Instruction::Div { .. }
+            | Instruction::Divu { .. } => bit(HI) | bit(LO),
+            ref instruction => instruction.destination().map_or(0, bit),
+        }
+    }
+}
+
+// How control leaves a basic block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    // bne, or beq between different registers
+    Branch { taken: u32, fallthrough: u32 },
+    // beq between a register and itself
+    Jump(u32),
+    Fallthrough(u32),
+    // jr $31
+    Return,
+    // jr through any other register, with the target if a lis in the same
+    // block loaded it
+    TailCall(Option<u32>),
+    // Runs into data or off the end of .text
+    Stop,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: u32,
+    pub ops: Vec<Op>,
+    pub exit: Exit,
+    pub successors: Vec<usize>,
+    pub predecessors: Vec<usize>,
+    // Targets of the jalrs in this block, where a lis in the block loaded them
+    pub calls: Vec<Option<u32>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    // Blocks reachable from the entry without following calls, entry first
+    pub blocks: Vec<usize>,
+}
+
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub functions: Vec<Function>,
+    by_address: HashMap<u32, usize>,
+}
+
+// The constant each register holds at the end of `ops`, for registers last
+// written by a lis
+fn constants(ops: &[Op]) -> HashMap<u8, u32> {
+    let mut result = HashMap::new();
+    for op in ops {
+        if op.is_call() {
+            result.clear();
+        }
+        if let Some(reg) = op.instruction.destination() {
+            match op.value {
+                Some(value) => result.insert(reg, value),
+                None => result.remove(&reg),
+            };
+        }
+    }
+    result
+}
+
+fn branch_target(op: &Op) -> u32 {
+    let offset = match op.instruction {
+        Instruction::Beq {
+            i: Value::Literal(i),
+            ..
+        }
+        | Instruction::Bne {
+            i: Value::Literal(i),
+            ..
+        } => i as u16 as i16 as i32,
+        _ => unreachable!(),
+    };
+    (op.address as i32 + 4 + 4 * offset) as u32
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Beq { .. } | Instruction::Bne { .. } | Instruction::Jr { .. }
+    )
+}
+
+// Addresses of the labels held in a .word, whether loaded by a lis or stored
+// in data. Code can reach them through any register, so each is a function
+// entry.
+pub fn address_taken(lines: &[Line], labels: &HashMap<&str, u32>) -> HashSet<u32> {
+    lines
+        .iter()
+        .filter_map(|line| match line.instruction {
+            Instruction::Word {
+                i: Value::Label(ref label),
+            } => labels.get(label.as_str()).copied(),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Cfg {
+    // Builds the graph from lines that have been through replace_labels,
+    // given the address_taken of the lines before they were.
+    // Only .text is code; a .word there that doesn't belong to a lis is data.
+    pub fn new(lines: &[Line], symbols: &SymbolTable, address_taken: &HashSet<u32>) -> Cfg {
+        let mut text: Vec<usize> = (0..lines.len())
+            .filter(|idx| lines[*idx].section == Section::Text)
+            .collect();
+        text.sort_by_key(|idx| lines[*idx].address);
+
+        // Runs of contiguous ops, split wherever data or a gap interrupts them
+        let mut runs: Vec<Vec<Op>> = vec![Vec::new()];
+        let mut position = 0;
+        while position < text.len() {
+            let line = &lines[text[position]];
+            position += 1;
+            let op = match line.instruction {
+                Instruction::Word { .. } => None,
+                Instruction::Lis { .. } => {
+                    let next = text.get(position).map(|idx| &lines[*idx]);
+                    match next {
+                        Some(Line {
+                            instruction: Instruction::Word { i },
+                            address,
+                            ..
+                        }) if *address == line.address + 4 => {
+                            position += 1;
+                            Some(Some(i.to_u32()))
+                        }
+                        _ => Some(None),
+                    }
+                }
+                _ => Some(None),
+            };
+            let Some(value) = op else {
+                runs.push(Vec::new());
+                continue;
+            };
+            let run = runs.last_mut().unwrap();
+            if run
+                .last()
+                .is_some_and(|last| last.address + last.size() != line.address)
+            {
+                runs.push(Vec::new());
+            }
+            runs.last_mut().unwrap().push(Op {
+                address: line.address,
+                instruction: line.instruction.clone(),
+                value,
+            });
+        }
+        runs.retain(|run| !run.is_empty());
+
+        // Blocks start at the start of a run, after a branch or jump, at a
+        // branch target, at the target of a jr or jalr whose register a lis
+        // loaded and at every address taken
+        let mut leaders: HashSet<u32> = address_taken.clone();
+        for run in &runs {
+            leaders.insert(run[0].address);
+            for (idx, op) in run.iter().enumerate() {
+                if ends_block(&op.instruction) {
+                    leaders.insert(op.address + 4);
+                }
+                match op.instruction {
+                    Instruction::Beq { .. } | Instruction::Bne { .. } => {
+                        leaders.insert(branch_target(op));
+                    }
+                    Instruction::Jr { s } | Instruction::Jalr { s } => {
+                        leaders.extend(constants(&run[..idx]).get(&s));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut blocks = Vec::new();
+        for run in runs {
+            let run_end = run.last().map(|op| op.address + op.size()).unwrap();
+            let mut ops: Vec<Op> = Vec::new();
+            for (idx, op) in run.iter().enumerate() {
+                ops.push(op.clone());
+                let next = run.get(idx + 1);
+                if next.is_some_and(|next| !leaders.contains(&next.address)) {
+                    continue;
+                }
+                let last = ops.last().unwrap();
+                let end = last.address + last.size();
+                let exit = match last.instruction {
+                    Instruction::Beq { s, t, .. } if s == t => Exit::Jump(branch_target(last)),
+                    // Never taken
+                    Instruction::Bne { s, t, .. } if s == t && end < run_end => {
+                        Exit::Fallthrough(end)
+                    }
+                    Instruction::Bne { s, t, .. } if s == t => Exit::Stop,
+                    Instruction::Beq { .. } | Instruction::Bne { .. } => Exit::Branch {
+                        taken: branch_target(last),
+                        fallthrough: end,
+                    },
+                    Instruction::Jr { s: 31 } => Exit::Return,
+                    Instruction::Jr { s } => {
+                        let known = constants(&ops[..ops.len() - 1]);
+                        Exit::TailCall(known.get(&s).copied())
+                    }
+                    _ if end < run_end => Exit::Fallthrough(end),
+                    _ => Exit::Stop,
+                };
+                let mut calls = Vec::new();
+                for (idx, op) in ops.iter().enumerate() {
+                    if let Instruction::Jalr { s } = op.instruction {
+                        calls.push(constants(&ops[..idx]).get(&s).copied());
+                    }
+                }
+                blocks.push(Block {
+                    start: ops[0].address,
+                    ops: std::mem::take(&mut ops),
+                    exit,
+                    successors: Vec::new(),
+                    predecessors: Vec::new(),
+                    calls,
+                });
+            }
+        }
+
+        let by_address: HashMap<u32, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (block.start, idx))
+            .collect();
+        for idx in 0..blocks.len() {
+            let targets = match blocks[idx].exit {
+                Exit::Branch { taken, fallthrough } => vec![taken, fallthrough],
+                Exit::Jump(target) | Exit::Fallthrough(target) => vec![target],
+                Exit::Return | Exit::TailCall(_) | Exit::Stop => Vec::new(),
+            };
+            let mut successors: Vec<usize> = targets
+                .iter()
+                .filter_map(|target| by_address.get(target).copied())
+                .collect();
+            successors.dedup();
+            for successor in &successors {
+                blocks[*successor].predecessors.push(idx);
+            }
+            blocks[idx].successors = successors;
+        }
+
+        let mut cfg = Cfg {
+            blocks,
+            functions: Vec::new(),
+            by_address,
+        };
+        cfg.functions = cfg.find_functions(symbols, address_taken);
+        cfg
+    }
+
+    // Functions start at address 0, at known call and tail call targets, at
+    // addresses taken, and at the first of any blocks none of those reach
+    fn find_functions(&self, symbols: &SymbolTable, address_taken: &HashSet<u32>) -> Vec<Function> {
+        let mut entries: BTreeSet<u32> = address_taken.iter().copied().collect();
+        if self.by_address.contains_key(&0) {
+            entries.insert(0);
+        }
+        for block in &self.blocks {
+            let tail_call = match block.exit {
+                Exit::TailCall(target) => target,
+                _ => None,
+            };
+            entries.extend(block.calls.iter().flatten().chain(tail_call.iter()));
+        }
+        entries.retain(|addr| self.by_address.contains_key(addr));
+
+        let mut functions = Vec::new();
+        let mut reached = HashSet::new();
+        let mut build = |entry: usize, reached: &mut HashSet<usize>| {
+            let mut blocks = Vec::new();
+            let mut seen = HashSet::from([entry]);
+            let mut queue = VecDeque::from([entry]);
+            while let Some(block) = queue.pop_front() {
+                blocks.push(block);
+                reached.insert(block);
+                for successor in &self.blocks[block].successors {
+                    if seen.insert(*successor) {
+                        queue.push_back(*successor);
+                    }
+                }
+            }
+            functions.push(Function {
+                name: symbols.describe(self.blocks[entry].start),
+                entry,
+                blocks,
+            });
+        };
+        for entry in entries {
+            build(self.by_address[&entry], &mut reached);
+        }
+        for idx in 0..self.blocks.len() {
+            if !reached.contains(&idx) {
+                build(idx, &mut reached);
+            }
+        }
+        functions
+    }
+
+    pub fn block_at(&self, addr: u32) -> Option<usize> {
+        self.by_address.get(&addr).copied()
+    }
+
+    // Whether control can leave the block to code the graph doesn't see
+    fn leaves_function(&self, block: usize) -> bool {
+        matches!(
+            self.blocks[block].exit,
+            Exit::Return | Exit::TailCall(_) | Exit::Stop
+        )
+    }
+
+    pub fn liveness(&self) -> Liveness {
+        let count = self.blocks.len();
+        let mut live = Liveness {
+            live_in: vec![0; count],
+            live_out: vec![0; count],
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for idx in (0..count).rev() {
+                let block = &self.blocks[idx];
+                // Callers and tail called functions may read anything
+                let mut out = if self.leaves_function(idx) { ALL } else { 0 };
+                for successor in &block.successors {
+                    out |= live.live_in[*successor];
+                }
+                let mut set = out;
+                for op in block.ops.iter().rev() {
+                    set = (set & !op.defs()) | op.uses();
+                }
+                if out != live.live_out[idx] || set != live.live_in[idx] {
+                    live.live_out[idx] = out;
+                    live.live_in[idx] = set;
+                    changed = true;
+                }
+            }
+        }
+        live
+    }
+
+    pub fn reaching_definitions(&self) -> ReachingDefinitions {
+        let count = self.blocks.len();
+        let mut reaching = ReachingDefinitions {
+            reach_in: vec![BTreeSet::new(); count],
+            reach_out: vec![BTreeSet::new(); count],
+        };
+        let entries: HashSet<usize> = self.functions.iter().map(|f| f.entry).collect();
+        let entry_definitions: BTreeSet<Definition> = (1..=LO)
+            .map(|register| Definition {
+                address: ENTRY,
+                register,
+            })
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for idx in 0..count {
+                let block = &self.blocks[idx];
+                let mut set = if entries.contains(&idx) {
+                    entry_definitions.clone()
+                } else {
+                    BTreeSet::new()
+                };
+                for predecessor in &block.predecessors {
+                    set.extend(reaching.reach_out[*predecessor].iter().copied());
+                }
+                let reach_in = set.clone();
+                for op in &block.ops {
+                    define(&mut set, op);
+                }
+                if reach_in != reaching.reach_in[idx] || set != reaching.reach_out[idx] {
+                    reaching.reach_in[idx] = reach_in;
+                    reaching.reach_out[idx] = set;
+                    changed = true;
+                }
+            }
+        }
+        reaching
+    }
+
+    // The instruction with the definitions reaching each register it reads,
+    // marked dead if nothing reads its result
+    fn describe_op(&self, block: usize, index: usize, analysis: &Analysis) -> String {
+        let op = &self.blocks[block].ops[index];
+        let mut text = op.instruction.format(analysis.names);
+        let live_after = analysis.liveness.live_after(self, block, index);
+        if op.is_pure() && op.defs() & live_after == 0 {
+            text += "  (dead)";
+        }
+        if op.is_call() {
+            return text;
+        }
+        let reaching = analysis.reaching.reaching_before(self, block, index);
+        let mut sources = Vec::new();
+        for register in 1..=LO {
+            if op.uses() & bit(register) == 0 {
+                continue;
+            }
+            let addresses: Vec<String> = reaching
+                .iter()
+                .filter(|definition| definition.register == register)
+                .map(|definition| match definition.address {
+                    ENTRY => String::from("entry"),
+                    address => format!("0x{address:x}"),
+                })
+                .collect();
+            let from = addresses.join("|");
+            let name = format_registers(bit(register), analysis.names);
+            sources.push(format!("{name} from {from}"));
+        }
+        if !sources.is_empty() {
+            text += &format!("  ({})", sources.join(", "));
+        }
+        text
+    }
+
+    fn dot_node(&self, idx: usize, analysis: &Analysis) -> String {
+        let block = &self.blocks[idx];
+        let (live, names) = (&analysis.liveness, analysis.names);
+        let mut label = format!("0x{:08x}\\l", block.start);
+        label += &format!("live in: {}\\l", format_registers(live.live_in[idx], names));
+        for (index, op) in block.ops.iter().enumerate() {
+            label += &format!("  {}\\l", self.describe_op(idx, index, analysis));
+            if let Some(value) = op.value {
+                label += &format!("  .word 0x{value:08x}\\l");
+            }
+        }
+        label += &format!(
+            "live out: {}\\l",
+            format_registers(live.live_out[idx], names)
+        );
+        format!("  b{idx} [label=\"{}\"];\n", label.replace('"', "\\\""))
+    }
+
+    // One digraph for a function, with the dataflow results on every block
+    pub fn to_dot(&self, function: &Function, analysis: &Analysis) -> String {
+        let mut dot = format!("digraph \"{}\" {{\n", function.name.replace('"', "\\\""));
+        dot += "  node [shape=box, fontname=\"monospace\"];\n";
+        dot += &format!("  entry [shape=oval, label=\"{}\"];\n", function.name);
+        dot += &format!("  entry -> b{};\n", function.entry);
+        for idx in &function.blocks {
+            dot += &self.dot_node(*idx, analysis);
+        }
+        for idx in &function.blocks {
+            let block = &self.blocks[*idx];
+            let block_edge = |target: u32, label: &'static str| {
+                self.block_at(target)
+                    .map(|target| (format!("b{target}"), label))
+            };
+            // Branches to addresses that aren't code have no edge
+            let edges: Vec<(String, &str)> = match block.exit {
+                Exit::Branch { taken, fallthrough } => [
+                    block_edge(taken, "taken"),
+                    block_edge(fallthrough, "not taken"),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+                Exit::Jump(target) | Exit::Fallthrough(target) => {
+                    block_edge(target, "").into_iter().collect()
+                }
+                Exit::Return => vec![(String::from("exit"), "return")],
+                Exit::TailCall(_) => vec![(String::from("exit"), "tail call")],
+                Exit::Stop => Vec::new(),
+            };
+            for (target, label) in edges {
+                dot += &format!("  b{idx} -> {target}");
+                match label {
+                    "" => dot += ";\n",
+                    label => dot += &format!(" [label=\"{label}\"];\n"),
+                }
+            }
+        }
+        if function
+            .blocks
+            .iter()
+            .any(|idx| matches!(self.blocks[*idx].exit, Exit::Return | Exit::TailCall(_)))
+        {
+            dot += "  exit [shape=oval];\n";
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+pub struct Analysis {
+    pub liveness: Liveness,
+    pub reaching: ReachingDefinitions,
+    pub names: RegisterNames,
+}
+
+// Registers live on entry to and exit from each block
+pub struct Liveness {
+    pub live_in: Vec<RegisterSet>,
+    pub live_out: Vec<RegisterSet>,
+}
+
+impl Liveness {
+    // Registers live right after the op at `index` in `block`
+    pub fn live_after(&self, cfg: &Cfg, block: usize, index: usize) -> RegisterSet {
+        let mut set = self.live_out[block];
+        for op in cfg.blocks[block].ops[index + 1..].iter().rev() {
+            set = (set & !op.defs()) | op.uses();
+        }
+        set
+    }
+}
+
+// The address of the definitions standing for values a function was entered with
+pub const ENTRY: u32 = u32::MAX;
+
+// A register written by the op at `address`, or holding the value it had on
+// entry to the function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Definition {
+    pub address: u32,
+    pub register: u8,
+}
+
+fn define(set: &mut BTreeSet<Definition>, op: &Op) {
+    let defs = op.defs();
+    set.retain(|definition| defs & bit(definition.register) == 0);
+    for register in 1..=LO {
+        if defs & bit(register) != 0 {
+            set.insert(Definition {
+                address: op.address,
+                register,
+            });
+        }
+    }
+}
+
+// Definitions that reach the start and end of each block
+pub struct ReachingDefinitions {
+    pub reach_in: Vec<BTreeSet<Definition>>,
+    pub reach_out: Vec<BTreeSet<Definition>>,
+}
+
+impl ReachingDefinitions {
+    // Definitions reaching the op at `index` in `block`, before it runs
+    pub fn reaching_before(&self, cfg: &Cfg, block: usize, index: usize) -> BTreeSet<Definition> {
+        let mut set = self.reach_in[block].clone();
+        for op in &cfg.blocks[block].ops[..index] {
+            define(&mut set, op);
+        }
+        set
+    }
+}
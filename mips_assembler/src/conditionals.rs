@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+// Names set with -D on the command line
+pub type Defines = HashMap<String, i64>;
+
+pub fn parse_define(define: &str) -> Option<(String, i64)> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    if name.is_empty() {
+        return None;
+    }
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    Some((name.to_string(), value))
+}
+
+struct Block {
+    line_number: usize,
+    // Whether the enclosing block is being assembled
+    outer_active: bool,
+    active: bool,
+    // Whether one of the branches so far was taken
+    taken: bool,
+    seen_else: bool,
+}
+
+// Tracks .if/.ifdef/.ifndef/.elif/.else/.endif while a file is being read so
+// that lines in disabled branches never reach the parser
+pub struct Conditionals<'a> {
+    defines: &'a Defines,
+    blocks: Vec<Block>,
+}
+
+impl<'a> Conditionals<'a> {
+    pub fn new(defines: &'a Defines) -> Conditionals<'a> {
+        Conditionals {
+            defines,
+            blocks: Vec::new(),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.active)
+    }
+
+    fn open(&mut self, condition: bool, line_number: usize) {
+        let outer_active = self.active();
+        self.blocks.push(Block {
+            line_number,
+            outer_active,
+            active: outer_active && condition,
+            taken: condition,
+            seen_else: false,
+        });
+    }
+
+    fn current(&mut self, directive: &str, line_number: usize) -> &mut Block {
+        match self.blocks.last_mut() {
+            Some(block) if !block.seen_else => block,
+            Some(_) => panic!("{directive} after .else on line {line_number}"),
+            None => panic!("{directive} without .if on line {line_number}"),
+        }
+    }
+
+    // Returns whether the line should be assembled
+    pub fn include(&mut self, line: &str, line_number: usize) -> bool {
+        let line = line[..line.find(';').unwrap_or(line.len())].trim();
+        let (directive, rest) = line
+            .split_once(char::is_whitespace)
+            .map(|(directive, rest)| (directive, rest.trim()))
+            .unwrap_or((line, ""));
+        let name = || {
+            if rest.is_empty() || rest.contains(char::is_whitespace) {
+                panic!("Expected {directive} <name> on line {line_number}");
+            }
+            rest
+        };
+        match directive {
+            ".if" => {
+                // Expressions inside disabled blocks may use names that
+                // only exist in the other variant
+                let condition = self.active() && self.evaluate(rest, line_number) != 0;
+                self.open(condition, line_number);
+            }
+            ".ifdef" => {
+                let condition = self.defines.contains_key(name());
+                self.open(condition, line_number);
+            }
+            ".ifndef" => {
+                let condition = !self.defines.contains_key(name());
+                self.open(condition, line_number);
+            }
+            ".elif" => {
+                let block = self.current(".elif", line_number);
+                let pending = block.outer_active && !block.taken;
+                let condition = pending && self.evaluate(rest, line_number) != 0;
+                let block = self.current(".elif", line_number);
+                block.active = condition;
+                block.taken |= condition;
+            }
+            ".else" => {
+                let block = self.current(".else", line_number);
+                block.active = block.outer_active && !block.taken;
+                block.taken = true;
+                block.seen_else = true;
+            }
+            ".endif" => {
+                if self.blocks.pop().is_none() {
+                    panic!(".endif without .if on line {line_number}");
+                }
+            }
+            _ => return self.active(),
+        }
+        false
+    }
+
+    pub fn finish(self) {
+        if let Some(block) = self.blocks.last() {
+            panic!("Missing .endif for .if on line {}", block.line_number);
+        }
+    }
+
+    fn evaluate(&self, expression: &str, line_number: usize) -> i64 {
+        let mut parser = Parser {
+            tokens: tokenize(expression, line_number),
+            position: 0,
+            defines: self.defines,
+            line_number,
+        };
+        let value = parser.or();
+        if let Some(token) = parser.tokens.get(parser.position) {
+            panic!("Unexpected {token} in .if expression on line {line_number}");
+        }
+        value
+    }
+}
+
+fn tokenize(expression: &str, line_number: usize) -> Vec<String> {
+    const OPERATORS: [&str; 18] = [
+        "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "+", "-", "*", "/", "%", "!",
+        "(", ")",
+    ];
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let len = if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            op.len()
+        } else {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        };
+        if len == 0 {
+            panic!("Unexpected character in .if expression on line {line_number}");
+        }
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    defines: &'a Defines,
+    line_number: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> String {
+        let token =
+            self.tokens.get(self.position).cloned().unwrap_or_else(|| {
+                panic!("Incomplete .if expression on line {}", self.line_number)
+            });
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &str) {
+        let token = self.next();
+        if token != expected {
+            panic!(
+                "Expected {expected} but found {token} in .if expression on line {}",
+                self.line_number
+            );
+        }
+    }
+
+    // Parses a left-associative chain of binary operators
+    fn binary(
+        &mut self,
+        operators: &[&str],
+        operand: fn(&mut Self) -> i64,
+        apply: fn(&str, i64, i64) -> Option<i64>,
+    ) -> i64 {
+        let mut value = operand(self);
+        while let Some(op) = self.peek().filter(|token| operators.contains(token)) {
+            let op = op.to_string();
+            self.position += 1;
+            let rhs = operand(self);
+            value = apply(&op, value, rhs).unwrap_or_else(|| {
+                panic!(
+                    "Division by zero in .if expression on line {}",
+                    self.line_number
+                )
+            });
+        }
+        value
+    }
+
+    fn or(&mut self) -> i64 {
+        self.binary(&["||"], Self::and, |_, a, b| {
+            Some((a != 0 || b != 0) as i64)
+        })
+    }
+
+    fn and(&mut self) -> i64 {
+        self.binary(&["&&"], Self::comparison, |_, a, b| {
+            Some((a != 0 && b != 0) as i64)
+        })
+    }
+
+    fn comparison(&mut self) -> i64 {
+        self.binary(
+            &["==", "!=", "<", "<=", ">", ">="],
+            Self::shift,
+            |op, a, b| {
+                Some(match op {
+                    "==" => a == b,
+                    "!=" => a != b,
+                    "<" => a < b,
+                    "<=" => a <= b,
+                    ">" => a > b,
+                    _ => a >= b,
+                } as i64)
+            },
+        )
+    }
+
+    fn shift(&mut self) -> i64 {
+        self.binary(&["<<", ">>"], Self::sum, |op, a, b| match op {
+            "<<" => Some(a.wrapping_shl(b as u32)),
+            _ => Some(a.wrapping_shr(b as u32)),
+        })
+    }
+
+    fn sum(&mut self) -> i64 {
+        self.binary(&["+", "-"], Self::product, |op, a, b| match op {
+            "+" => Some(a.wrapping_add(b)),
+            _ => Some(a.wrapping_sub(b)),
+        })
+    }
+
+    fn product(&mut self) -> i64 {
+        self.binary(&["*", "/", "%"], Self::unary, |op, a, b| match op {
+            "*" => Some(a.wrapping_mul(b)),
+            "/" => a.checked_div(b),
+            _ => a.checked_rem(b),
+        })
+    }
+
+    fn unary(&mut self) -> i64 {
+        match self.peek() {
+            Some("!") => {
+                self.position += 1;
+                (self.unary() == 0) as i64
+            }
+            Some("-") => {
+                self.position += 1;
+                self.unary().wrapping_neg()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> i64 {
+        let token = self.next();
+        if token == "(" {
+            let value = self.or();
+            self.expect(")");
+            return value;
+        }
+        if token == "defined" {
+            self.expect("(");
+            let name = self.next();
+            self.expect(")");
+            return self.defines.contains_key(&name) as i64;
+        }
+        let number = match token.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => token.parse().ok(),
+        };
+        if let Some(number) = number {
+            return number;
+        }
+        if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            return *self
+                .defines
+                .get(&token)
+                .unwrap_or_else(|| panic!("{token} is not defined on line {}", self.line_number));
+        }
+        panic!(
+            "Unexpected {token} in .if expression on line {}",
+            self.line_number
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{Instruction, Line};
+
+#[derive(Debug, Default, Clone, Copy)]
+struct BranchCounts {
+    taken: u64,
+    not_taken: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    executed: HashMap<u32, u64>,
+    branches: HashMap<u32, BranchCounts>,
+}
+
+// Per source line with code: the execution count and the branch directions
+// if the line is a conditional branch.
+struct LineCoverage {
+    count: u64,
+    branch: Option<BranchCounts>,
+}
+
+impl Coverage {
+    pub fn record_instruction(&mut self, pc: u32) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+    }
+
+    pub fn record_branch(&mut self, pc: u32, taken: bool) {
+        let counts = self.branches.entry(pc).or_default();
+        if taken {
+            counts.taken += 1;
+        } else {
+            counts.not_taken += 1;
+        }
+    }
+
+    // `lines` are the resolved program lines, in address order
+    fn by_source_line(&self, lines: &[Line], file: usize) -> HashMap<usize, LineCoverage> {
+        let mut result = HashMap::new();
+        let mut previous_count = 0;
+        let mut previous_was_lis = false;
+        for line in lines {
+            let addr = line.address;
+            let mut count = self.executed.get(&addr).copied().unwrap_or(0);
+            // The word after a lis is loaded as data rather than fetched
+            if previous_was_lis {
+                count = previous_count;
+            }
+            // Branches comparing a register with itself have only one direction
+            let branch = match line.instruction {
+                Instruction::Beq { s, t, .. } | Instruction::Bne { s, t, .. }
+                    if s != t && !previous_was_lis =>
+                {
+                    Some(self.branches.get(&addr).copied().unwrap_or_default())
+                }
+                _ => None,
+            };
+            previous_was_lis =
+                !previous_was_lis && matches!(line.instruction, Instruction::Lis { .. });
+            previous_count = count;
+            if line.file == file {
+                result.insert(line.line_number, LineCoverage { count, branch });
+            }
+        }
+        result
+    }
+
+    pub fn annotated_source(&self, source: &str, lines: &[Line], file: usize) -> String {
+        let coverage = self.by_source_line(lines, file);
+        let mut result = String::new();
+        let mut partial_branches = Vec::new();
+        for (idx, text) in source.lines().enumerate() {
+            let line_number = idx + 1;
+            let (count, note) = match coverage.get(&line_number) {
+                None => (String::from("-"), String::new()),
+                Some(LineCoverage { count, branch }) => {
+                    let count = match count {
+                        0 => String::from("#####"),
+                        count => count.to_string(),
+                    };
+                    let note = match branch {
+                        Some(branch) => {
+                            let note = match (branch.taken, branch.not_taken) {
+                                (0, 0) => String::from("branch never executed"),
+                                (0, _) => String::from("branch never taken"),
+                                (_, 0) => String::from("branch always taken"),
+                                _ => String::new(),
+                            };
+                            if !note.is_empty() {
+                                partial_branches.push(format!("  line {line_number}: {note}"));
+                            }
+                            format!(
+                                "  ; [taken {}, not taken {}] {note}",
+                                branch.taken, branch.not_taken
+                            )
+                        }
+                        None => String::new(),
+                    };
+                    (count, note)
+                }
+            };
+            writeln!(result, "{count:>9}: {line_number:>5}: {text}{note}").unwrap();
+        }
+
+        let found = coverage.len();
+        let hit = coverage.values().filter(|line| line.count > 0).count();
+        let branches = coverage
+            .values()
+            .filter(|line| line.branch.is_some())
+            .count();
+        writeln!(result).unwrap();
+        writeln!(result, "Lines executed: {hit} of {found}").unwrap();
+        writeln!(
+            result,
+            "Branches taken both ways: {} of {branches}",
+            branches - partial_branches.len()
+        )
+        .unwrap();
+        for branch in partial_branches {
+            writeln!(result, "{branch}").unwrap();
+        }
+        result
+    }
+
+    pub fn lcov(&self, source_path: &str, lines: &[Line], file: usize) -> String {
+        let coverage = self.by_source_line(lines, file);
+        let mut line_numbers: Vec<&usize> = coverage.keys().collect();
+        line_numbers.sort();
+
+        let mut result = String::new();
+        writeln!(result, "TN:").unwrap();
+        writeln!(result, "SF:{source_path}").unwrap();
+        let (mut branches_found, mut branches_hit) = (0, 0);
+        for line_number in &line_numbers {
+            let line = &coverage[*line_number];
+            if let Some(branch) = line.branch {
+                let executed = line.count > 0;
+                for (idx, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                    let count = if executed {
+                        count.to_string()
+                    } else {
+                        String::from("-")
+                    };
+                    writeln!(result, "BRDA:{line_number},0,{idx},{count}").unwrap();
+                }
+                branches_found += 2;
+                branches_hit += (branch.taken > 0) as u32 + (branch.not_taken > 0) as u32;
+            }
+        }
+        writeln!(result, "BRF:{branches_found}").unwrap();
+        writeln!(result, "BRH:{branches_hit}").unwrap();
+        let mut hit = 0;
+        for line_number in &line_numbers {
+            let count = coverage[*line_number].count;
+            if count > 0 {
+                hit += 1;
+            }
+            writeln!(result, "DA:{line_number},{count}").unwrap();
+        }
+        writeln!(result, "LF:{}", line_numbers.len()).unwrap();
+        writeln!(result, "LH:{hit}").unwrap();
+        writeln!(result, "end_of_record").unwrap();
+        result
+    }
+}
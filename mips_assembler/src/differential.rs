@@ -0,0 +1,95 @@
+use crate::mmio::SharedBuffer;
+use crate::MipsEmulator;
+
+// Differences in registers, pc and instruction count
+fn state_differences(reference: &MipsEmulator, other: &MipsEmulator) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (idx, (a, b)) in reference.registers.iter().zip(other.registers).enumerate() {
+        if *a != b {
+            differences.push(format!("${idx}: 0x{a:08x} != 0x{b:08x}"));
+        }
+    }
+    for (name, a, b) in [
+        ("hi", reference.hi, other.hi),
+        ("lo", reference.lo, other.lo),
+        ("pc", reference.pc, other.pc),
+    ] {
+        if a != b {
+            differences.push(format!("{name}: 0x{a:08x} != 0x{b:08x}"));
+        }
+    }
+    if reference.steps != other.steps {
+        differences.push(format!(
+            "instructions executed: {} != {}",
+            reference.steps, other.steps
+        ));
+    }
+    if reference.exit_status != other.exit_status {
+        differences.push(format!(
+            "exit status: {:?} != {:?}",
+            reference.exit_status, other.exit_status
+        ));
+    }
+    differences
+}
+
+// Differences in architectural state between two finished runs
+pub fn compare(reference: &MipsEmulator, other: &MipsEmulator) -> Vec<String> {
+    let mut differences = state_differences(reference, other);
+    let mut addresses: Vec<u32> = reference
+        .memory
+        .keys()
+        .chain(other.memory.keys())
+        .copied()
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    for addr in addresses {
+        let (a, b) = (reference.memory.get(&addr), other.memory.get(&addr));
+        if a != b {
+            differences.push(format!("memory 0x{:08x}: {a:?} != {b:?}", addr * 4));
+        }
+    }
+    differences
+}
+
+// Runs the JIT one block at a time, catching the reference interpreter up
+// after each block and comparing their state. Returns false at the first
+// divergence, or if memory or program output differ at the end.
+pub fn run(reference: (MipsEmulator, SharedBuffer), subject: (MipsEmulator, SharedBuffer)) -> bool {
+    let (mut reference, reference_output) = reference;
+    let (mut subject, subject_output) = subject;
+    while !subject.halted() {
+        let (start, first) = (subject.pc, subject.steps + 1);
+        subject.dispatch();
+        while reference.steps < subject.steps && reference.step() {}
+        let differences = state_differences(&reference, &subject);
+        if !differences.is_empty() {
+            eprintln!(
+                "differential: diverged in the block at 0x{start:08x} (instructions {first} to {})",
+                subject.steps
+            );
+            for difference in differences {
+                eprintln!("  {difference}");
+            }
+            return false;
+        }
+    }
+    reference.devices.flush();
+    subject.devices.flush();
+
+    let mut differences = compare(&reference, &subject);
+    if reference_output.contents() != subject_output.contents() {
+        differences.push(String::from("program output differs"));
+    }
+    for difference in &differences {
+        eprintln!("differential: {difference}");
+    }
+    if differences.is_empty() {
+        eprintln!(
+            "differential: {} instructions executed identically",
+            subject.steps
+        );
+    }
+    differences.is_empty()
+}
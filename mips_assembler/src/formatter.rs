@@ -0,0 +1,268 @@
+use std::fs;
+
+// Indentation of instructions and data
+const INDENT: &str = "  ";
+// Operands start this many columns after the mnemonic
+const MNEMONIC_WIDTH: usize = 7;
+// Column of comments that follow code
+const COMMENT_COLUMN: usize = 32;
+
+// Directives that control assembly rather than emit words stay in column 0
+const TOP_LEVEL: [&str; 11] = [
+    ".text", ".data", ".bss", ".org", ".reg", ".if", ".ifdef", ".ifndef", ".elif", ".else",
+    ".endif",
+];
+
+// A source line split into its parts, with operands kept as written so
+// register aliases and label names survive formatting
+enum SourceLine {
+    Blank,
+    Comment(String),
+    Code {
+        labels: Vec<String>,
+        statement: Option<(String, String)>,
+        comment: Option<String>,
+    },
+}
+
+// Lowercases hex digits and drops redundant signs and leading zeros from
+// decimal numbers; anything else (labels, registers) is left alone
+fn format_number(token: &str) -> String {
+    if let Some(hex) = token.strip_prefix("0x") {
+        if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return format!("0x{}", hex.to_ascii_lowercase());
+        }
+    } else if token
+        .trim_start_matches(['-', '+'])
+        .chars()
+        .all(|c| c.is_ascii_digit())
+    {
+        if let Ok(num) = token.parse::<i64>() {
+            return num.to_string();
+        }
+    }
+    token.to_string()
+}
+
+fn format_operands(mnemonic: &str, operands: &str) -> String {
+    if matches!(mnemonic, ".if" | ".elif") {
+        return operands.to_string();
+    }
+    if matches!(mnemonic, "lw" | "sw") {
+        let tokens: Vec<&str> = operands
+            .split([' ', '\t', ',', '(', ')'])
+            .filter(|s| !s.is_empty())
+            .collect();
+        if let [t, offset, s] = tokens[..] {
+            return format!("{t}, {}({s})", format_number(offset));
+        }
+    }
+    operands
+        .split([' ', '\t', ','])
+        .filter(|s| !s.is_empty())
+        .map(format_number)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn parse(line: &str) -> SourceLine {
+    let (code, comment) = match line.find(';') {
+        Some(idx) => (&line[..idx], Some(line[idx + 1..].trim_end().to_string())),
+        None => (line, None),
+    };
+    let code = code.trim();
+    if code.is_empty() {
+        return match comment {
+            Some(comment) => SourceLine::Comment(comment),
+            None => SourceLine::Blank,
+        };
+    }
+
+    // Everything before the last colon is a list of labels, as in parse_line
+    let last_colon_index = code.rfind(':').map(|x| x + 1).unwrap_or(0);
+    let mut labels: Vec<String> = code[..last_colon_index]
+        .split(':')
+        .map(|s| s.trim().to_string())
+        .collect();
+    labels.pop();
+    let instruction = code[last_colon_index..].trim();
+    let statement = (!instruction.is_empty()).then(|| {
+        let (mnemonic, operands) = instruction
+            .split_once(char::is_whitespace)
+            .unwrap_or((instruction, ""));
+        (
+            mnemonic.to_string(),
+            format_operands(mnemonic, operands.trim()),
+        )
+    });
+    SourceLine::Code {
+        labels,
+        statement,
+        comment,
+    }
+}
+
+fn indent(line: &SourceLine) -> &'static str {
+    match line {
+        SourceLine::Code { labels, .. } if !labels.is_empty() => "",
+        SourceLine::Code {
+            statement: Some((mnemonic, _)),
+            ..
+        } if TOP_LEVEL.contains(&mnemonic.as_str()) => "",
+        _ => INDENT,
+    }
+}
+
+fn with_comment(code: String, comment: &Option<String>) -> String {
+    match comment {
+        Some(comment) => {
+            let width = COMMENT_COLUMN.max(code.len() + 1);
+            format!("{code:width$};{comment}")
+        }
+        None => code,
+    }
+}
+
+fn format_statement(mnemonic: &str, operands: &str) -> String {
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else if TOP_LEVEL.contains(&mnemonic) {
+        format!("{mnemonic} {operands}")
+    } else {
+        format!("{mnemonic:MNEMONIC_WIDTH$}{operands}")
+    }
+}
+
+// Labels go on lines of their own in column 0, code is indented with its
+// operands in a common column, and trailing comments line up. Full-line
+// comments take the indentation of the code they describe. Runs of blank
+// lines collapse into one.
+pub fn format(source: &str) -> String {
+    let lines: Vec<SourceLine> = source.lines().map(parse).collect();
+    let mut output: Vec<String> = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        match line {
+            SourceLine::Blank => {
+                if output.last().is_some_and(|last| !last.is_empty()) {
+                    output.push(String::new());
+                }
+            }
+            SourceLine::Comment(comment) => {
+                let next_code = lines[idx + 1..]
+                    .iter()
+                    .find(|line| matches!(line, SourceLine::Code { .. }));
+                let indent = next_code.map(indent).unwrap_or("");
+                output.push(format!("{indent};{comment}"));
+            }
+            SourceLine::Code {
+                labels,
+                statement,
+                comment,
+            } => {
+                for (label_idx, label) in labels.iter().enumerate() {
+                    let last = label_idx + 1 == labels.len();
+                    if last && statement.is_none() {
+                        output.push(with_comment(format!("{label}:"), comment));
+                    } else {
+                        output.push(format!("{label}:"));
+                    }
+                }
+                if let Some((mnemonic, operands)) = statement {
+                    let indent = if TOP_LEVEL.contains(&mnemonic.as_str()) {
+                        ""
+                    } else {
+                        INDENT
+                    };
+                    let code = format!("{indent}{}", format_statement(mnemonic, operands));
+                    output.push(with_comment(code, comment));
+                }
+            }
+        }
+    }
+    while output.last().is_some_and(|last| last.is_empty()) {
+        output.pop();
+    }
+    let mut result = output.join("\n");
+    result.push('\n');
+    result
+}
+
+// `fmt [--check] <files>`: rewrites the files in place, or with --check only
+// reports the ones that would change. Returns false if any file is unformatted
+// in check mode.
+pub fn run(args: &[String]) -> bool {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() {
+        crate::usage();
+    }
+    let mut formatted = true;
+    for path in paths {
+        let source = fs::read_to_string(path).expect("Could not open MIPS file");
+        let result = format(&source);
+        if result == source {
+            continue;
+        }
+        if check {
+            let line = source
+                .lines()
+                .zip(result.lines())
+                .position(|(old, new)| old != new)
+                .unwrap_or_else(|| source.lines().count().min(result.lines().count()));
+            eprintln!("{path}:{} is not formatted", line + 1);
+            formatted = false;
+        } else {
+            fs::write(path, result).expect("Could not write MIPS file");
+        }
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for source in [
+            include_str!("../test.asm"),
+            include_str!("../height.asm"),
+            include_str!("../labels.asm"),
+            include_str!("../print.asm"),
+            include_str!("../alloc.asm"),
+            include_str!("../benchmarks/sort.asm"),
+        ] {
+            let formatted = format(source);
+            assert_eq!(format(&formatted), formatted);
+        }
+    }
+
+    #[test]
+    fn puts_labels_on_their_own_lines() {
+        assert_eq!(
+            format("label3: add $1,$2,$3\nlabel5: label6:\n"),
+            "label3:\n  add    $1, $2, $3\nlabel5:\nlabel6:\n"
+        );
+    }
+
+    #[test]
+    fn aligns_comments() {
+        assert_eq!(
+            format("; Header\nloop: ; Top\n   jr $31 ;Return\n\n\n"),
+            "; Header\nloop:                           ; Top\n  jr     $31                    ;Return\n"
+        );
+        // Full-line comments are indented like the code after them
+        assert_eq!(
+            format("label:\n; in: $1\nadd $3, $1, $0\n"),
+            "label:\n  ; in: $1\n  add    $3, $1, $0\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_numbers() {
+        assert_eq!(
+            format(".word 0xFFFF000C\nlw $1, +008($30)\n.word -012\n.word label\n"),
+            "  .word  0xffff000c\n  lw     $1, 8($30)\n  .word  -12\n  .word  label\n"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::symbols::SymbolTable;
+
+struct Block {
+    end: u32,
+    // Address of the jalr that allocated or freed the block
+    site: u32,
+}
+
+struct PendingNew {
+    return_address: u32,
+    words: u32,
+    site: u32,
+}
+
+// Watches calls into the alloc.asm runtime. Accesses made by the runtime
+// itself are ignored, since it keeps its bookkeeping inside freed blocks.
+pub struct HeapChecker {
+    symbols: SymbolTable,
+    new_address: u32,
+    delete_address: u32,
+    runtime: Range<u32>,
+    pending: Vec<PendingNew>,
+    live: BTreeMap<u32, Block>,
+    freed: BTreeMap<u32, Block>,
+    pub errors: Vec<String>,
+}
+
+fn find(blocks: &BTreeMap<u32, Block>, addr: u32) -> Option<(u32, &Block)> {
+    let (start, block) = blocks.range(..=addr).next_back()?;
+    if addr < block.end {
+        Some((*start, block))
+    } else {
+        None
+    }
+}
+
+impl HeapChecker {
+    pub fn new(symbols: SymbolTable, runtime: Range<u32>) -> Option<HeapChecker> {
+        let new_address = symbols.address_of("__alloc_new")?;
+        let delete_address = symbols.address_of("__alloc_delete")?;
+        Some(HeapChecker {
+            symbols,
+            new_address,
+            delete_address,
+            runtime,
+            pending: Vec::new(),
+            live: BTreeMap::new(),
+            freed: BTreeMap::new(),
+            errors: Vec::new(),
+        })
+    }
+
+    fn error(&mut self, message: String) {
+        eprintln!("heap error: {message}");
+        self.errors.push(message);
+    }
+
+    pub fn record_call(&mut self, target: u32, return_address: u32, argument: u32) {
+        let site = return_address.wrapping_sub(4);
+        if target == self.new_address {
+            self.pending.push(PendingNew {
+                return_address,
+                words: argument,
+                site,
+            });
+        } else if target == self.delete_address && argument != 0 {
+            self.record_delete(argument, site);
+        }
+    }
+
+    fn record_delete(&mut self, addr: u32, site: u32) {
+        if let Some(block) = self.live.remove(&addr) {
+            // A later allocation may reuse the memory, so forget overlapping frees
+            let stale: Vec<u32> = self
+                .freed
+                .range(addr..block.end)
+                .map(|(start, _)| *start)
+                .collect();
+            for start in stale {
+                self.freed.remove(&start);
+            }
+            self.freed.insert(
+                addr,
+                Block {
+                    end: block.end,
+                    site,
+                },
+            );
+        } else if let Some(previous) = self.freed.get(&addr) {
+            let message = format!(
+                "double free of 0x{addr:08x} at {}, already freed at {}",
+                self.symbols.describe(site),
+                self.symbols.describe(previous.site)
+            );
+            self.error(message);
+        } else {
+            let message = format!(
+                "delete of 0x{addr:08x} at {}, which was not returned by __alloc_new",
+                self.symbols.describe(site)
+            );
+            self.error(message);
+        }
+    }
+
+    // Called before each instruction to catch returns from new
+    pub fn record_instruction(&mut self, pc: u32, result: u32) {
+        if self.pending.last().map(|pending| pending.return_address) != Some(pc) {
+            return;
+        }
+        let pending = self.pending.pop().unwrap();
+        if result == 0 {
+            return;
+        }
+        let end = result.wrapping_add(4 * pending.words);
+        let stale: Vec<u32> = self
+            .freed
+            .range(..end)
+            .filter(|(_, block)| block.end > result)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in stale {
+            self.freed.remove(&start);
+        }
+        self.live.insert(
+            result,
+            Block {
+                end,
+                site: pending.site,
+            },
+        );
+    }
+
+    pub fn record_access(&mut self, addr: u32, pc: u32, is_write: bool) {
+        if self.runtime.contains(&pc) {
+            return;
+        }
+        if let Some((start, block)) = find(&self.freed, addr) {
+            let message = format!(
+                "{} of 0x{addr:08x} at {} after it was freed at {} (block 0x{start:08x})",
+                if is_write { "write" } else { "read" },
+                self.symbols.describe(pc),
+                self.symbols.describe(block.site)
+            );
+            self.error(message);
+        }
+    }
+
+    pub fn leak_report(&self) -> Vec<String> {
+        self.live
+            .iter()
+            .map(|(start, block)| {
+                format!(
+                    "leak: {} words at 0x{start:08x} allocated at {}",
+                    (block.end - start) / 4,
+                    self.symbols.describe(block.site)
+                )
+            })
+            .collect()
+    }
+}
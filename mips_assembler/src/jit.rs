@@ -0,0 +1,226 @@
+use crate::predecode::Op;
+use crate::MipsEmulator;
+
+// Executions through the predecoder before a block gets compiled
+pub const HOT_THRESHOLD: u32 = 16;
+
+// Runs one instruction. Returning false is a side exit: the instruction did
+// not run and the interpreter has to take over from its address. That happens
+// for memory-mapped I/O, uninitialized reads, stores into decoded code and
+// division by zero, so compiled code never needs devices, crash reporting or
+// cache invalidation.
+type CompiledOp = Box<dyn Fn(&mut MipsEmulator) -> bool>;
+
+// A basic block translated into a chain of closures with every register
+// index, immediate and branch target resolved at compile time.
+pub struct CompiledBlock {
+    ops: Vec<CompiledOp>,
+    addresses: Vec<u32>,
+    fallthrough: u32,
+}
+
+fn compile_op(op: Op, addr: u32) -> CompiledOp {
+    let next = addr + 4;
+    match op {
+        Op::Add { d, s, t } => {
+            let (d, s, t) = (d as usize, s as usize, t as usize);
+            Box::new(move |em| {
+                em.registers[d] = em.registers[s].wrapping_add(em.registers[t]);
+                true
+            })
+        }
+        Op::Sub { d, s, t } if s == t => Box::new(move |em| {
+            em.registers[d as usize] = 0;
+            true
+        }),
+        Op::Sub { d, s, t } => {
+            let (d, s, t) = (d as usize, s as usize, t as usize);
+            Box::new(move |em| {
+                em.registers[d] = em.registers[s].wrapping_sub(em.registers[t]);
+                true
+            })
+        }
+        Op::Slt { d, s, t } => {
+            let (d, s, t) = (d as usize, s as usize, t as usize);
+            Box::new(move |em| {
+                em.registers[d] = ((em.registers[s] as i32) < (em.registers[t] as i32)) as u32;
+                true
+            })
+        }
+        Op::Sltu { d, s, t } => {
+            let (d, s, t) = (d as usize, s as usize, t as usize);
+            Box::new(move |em| {
+                em.registers[d] = (em.registers[s] <= em.registers[t]) as u32;
+                true
+            })
+        }
+        Op::Mult { s, t } => {
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                let product = ((em.registers[s] as i64) * (em.registers[t] as i64)) as u64;
+                em.hi = (product >> 32) as u32;
+                em.lo = product as u32;
+                true
+            })
+        }
+        Op::Multu { s, t } => {
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                let product = (em.registers[s] as u64) * (em.registers[t] as u64);
+                em.hi = (product >> 32) as u32;
+                em.lo = product as u32;
+                true
+            })
+        }
+        Op::Div { s, t } => {
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                let (s, t) = (em.registers[s] as i32, em.registers[t] as i32);
+                if t == 0 {
+                    return false;
+                }
+                em.lo = (s / t) as u32;
+                em.hi = (s % t) as u32;
+                true
+            })
+        }
+        Op::Divu { s, t } => {
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                let (s, t) = (em.registers[s], em.registers[t]);
+                if t == 0 {
+                    return false;
+                }
+                em.lo = s / t;
+                em.hi = s % t;
+                true
+            })
+        }
+        Op::Mfhi { d } => Box::new(move |em| {
+            em.registers[d as usize] = em.hi;
+            true
+        }),
+        Op::Mflo { d } => Box::new(move |em| {
+            em.registers[d as usize] = em.lo;
+            true
+        }),
+        Op::Lis { d, value } => Box::new(move |em| {
+            em.registers[d as usize] = value;
+            true
+        }),
+        Op::Lw { t, s, offset } => {
+            let (t, s) = (t as usize, s as usize);
+            Box::new(move |em| {
+                let addr = (em.registers[s] as i32 + offset) as u32;
+                if em.devices.contains(addr) {
+                    return false;
+                }
+                match em.memory.get(&(addr / 4)) {
+                    Some(word) => {
+                        em.registers[t] = *word;
+                        true
+                    }
+                    None => false,
+                }
+            })
+        }
+        Op::Sw { t, s, offset } => {
+            let (t, s) = (t as usize, s as usize);
+            Box::new(move |em| {
+                let addr = (em.registers[s] as i32 + offset) as u32;
+                if em.devices.contains(addr) || em.blocks.is_code(addr / 4) {
+                    return false;
+                }
+                em.memory.insert(addr / 4, em.registers[t]);
+                true
+            })
+        }
+        Op::Beq { s, t, offset } => {
+            let target = (next as i32 + offset) as u32;
+            if s == t {
+                return Box::new(move |em| {
+                    em.pc = target;
+                    true
+                });
+            }
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                em.pc = if em.registers[s] == em.registers[t] {
+                    target
+                } else {
+                    next
+                };
+                true
+            })
+        }
+        Op::Bne { s, t, offset } => {
+            let target = (next as i32 + offset) as u32;
+            if s == t {
+                return Box::new(move |em| {
+                    em.pc = next;
+                    true
+                });
+            }
+            let (s, t) = (s as usize, t as usize);
+            Box::new(move |em| {
+                em.pc = if em.registers[s] != em.registers[t] {
+                    target
+                } else {
+                    next
+                };
+                true
+            })
+        }
+        Op::Jr { s } => Box::new(move |em| {
+            em.pc = em.registers[s as usize];
+            true
+        }),
+        Op::Jalr { s } => Box::new(move |em| {
+            let target = em.registers[s as usize];
+            em.registers[31] = next;
+            em.pc = target;
+            true
+        }),
+    }
+}
+
+pub fn compile(start: u32, ops: &[Op]) -> CompiledBlock {
+    let mut compiled = Vec::new();
+    let mut addresses = Vec::new();
+    let mut addr = start;
+    for op in ops {
+        compiled.push(compile_op(*op, addr));
+        addresses.push(addr);
+        addr += match op {
+            Op::Lis { .. } => 8,
+            _ => 4,
+        };
+    }
+    CompiledBlock {
+        ops: compiled,
+        addresses,
+        fallthrough: addr,
+    }
+}
+
+impl MipsEmulator {
+    // Returns false if the block took a side exit
+    pub(crate) fn run_compiled(&mut self, block: &CompiledBlock) -> bool {
+        self.pc = block.fallthrough;
+        for (idx, op) in block.ops.iter().enumerate() {
+            if !op(self) {
+                self.pc = block.addresses[idx];
+                self.retire(idx as u64);
+                return false;
+            }
+        }
+        self.retire(block.ops.len() as u64);
+        true
+    }
+
+    // Compiled blocks never touch devices, so their clock can catch up at the end
+    fn retire(&mut self, instructions: u64) {
+        self.steps += instructions;
+        self.devices.advance(instructions);
+    }
+}
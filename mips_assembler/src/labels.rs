@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use crate::Line;
+
+// Numeric local labels get file-unique names containing '#', which can't
+// appear in source labels
+pub fn is_numeric_local(label: &str) -> bool {
+    label.contains('#')
+}
+
+// The label as it was written, without the colon or the suffix numeric local
+// labels get when they are resolved
+pub fn source_name(label: &str) -> &str {
+    let label = label.strip_suffix(':').unwrap_or(label);
+    if is_numeric_local(label) {
+        label.split('#').next().unwrap_or(label)
+    } else {
+        label
+    }
+}
+
+fn numeric_name(number: &str, file: usize, occurrence: u32) -> String {
+    format!("{number}#{file}.{occurrence}")
+}
+
+// A reference to a numeric label, like 1f or 2b
+fn numeric_reference(label: &str) -> Option<(&str, bool)> {
+    let (number, direction) = label.split_at(label.len().checked_sub(1)?);
+    if number.is_empty() || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    match direction {
+        "f" => Some((number, true)),
+        "b" => Some((number, false)),
+        _ => None,
+    }
+}
+
+// Renames the local labels of one file and the references to them:
+//
+//   .name     is scoped to the last ordinary label, becoming outer.name
+//   1: ... 1b refers to the closest 1: at or before the reference
+//   1f        refers to the closest 1: after the line of the reference
+pub fn resolve_local_labels(lines: &mut [Line], file: usize) {
+    let mut totals: HashMap<String, u32> = HashMap::new();
+    for line in lines.iter() {
+        for label in &line.labels {
+            let name = &label[..label.len() - 1];
+            if name.bytes().all(|byte| byte.is_ascii_digit()) {
+                *totals.entry(name.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut scope: Option<String> = None;
+    let mut seen: HashMap<String, u32> = HashMap::new();
+    for line in lines.iter_mut() {
+        for label in line.labels.iter_mut() {
+            let name = label[..label.len() - 1].to_string();
+            let resolved = if name.bytes().all(|byte| byte.is_ascii_digit()) {
+                let count = seen.entry(name.clone()).or_insert(0);
+                *count += 1;
+                numeric_name(&name, file, *count)
+            } else if name.starts_with('.') {
+                match scope {
+                    Some(ref scope) => format!("{scope}{name}"),
+                    None => name,
+                }
+            } else {
+                scope = Some(name.clone());
+                name
+            };
+            *label = format!("{resolved}:");
+        }
+
+        let line_number = line.line_number;
+        let Some(label) = line.instruction.label_mut() else {
+            continue;
+        };
+        if let Some((number, forward)) = numeric_reference(label) {
+            let count = seen.get(number).copied().unwrap_or(0);
+            let occurrence = if forward { count + 1 } else { count };
+            if occurrence == 0 || occurrence > totals.get(number).copied().unwrap_or(0) {
+                let direction = if forward { "after" } else { "before" };
+                panic!("No label {number}: {direction} line {line_number} for {label}");
+            }
+            *label = numeric_name(number, file, occurrence);
+        } else if label.starts_with('.') {
+            if let Some(ref scope) = scope {
+                *label = format!("{scope}{label}");
+            }
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead};
+
+use crate::conditionals::{parse_define, Defines};
+use crate::labels::source_name;
+use crate::sections::{self, SectionBases};
+use crate::{extract_label_locations, parse_lines, Instruction, Line, Value};
+
+// Warnings are silenced for a single line with a comment like
+// `; lint: allow(zero-write, unused-label)`
+fn allowed(line: &Line, check: &str) -> bool {
+    let Some((_, comment)) = line.text.split_once(';') else {
+        return false;
+    };
+    let Some((_, rest)) = comment.split_once("lint: allow(") else {
+        return false;
+    };
+    let names = rest.split(')').next().unwrap_or("");
+    names.split(',').any(|name| name.trim() == check)
+}
+
+fn is_unconditional_jump(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::Beq { s, t, .. } => s == t,
+        Instruction::Jr { .. } => true,
+        _ => false,
+    }
+}
+
+fn is_control_transfer(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Beq { .. }
+            | Instruction::Bne { .. }
+            | Instruction::Jr { .. }
+            | Instruction::Jalr { .. }
+            | Instruction::Syscall
+    )
+}
+
+struct Linter<'a> {
+    lines: &'a Vec<Line>,
+    source_names: &'a [&'a str],
+    // (file, line number, message)
+    warnings: Vec<(usize, usize, String)>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, line: &Line, check: &str, message: String) {
+        if allowed(line, check) {
+            return;
+        }
+        let message = format!(
+            "{}:{}: warning: {message} [{check}]",
+            self.source_names[line.file], line.line_number
+        );
+        self.warnings.push((line.file, line.line_number, message));
+    }
+
+    // Whether control can only reach `to` by falling through from `from`,
+    // i.e. there are no labels or directives in between
+    fn falls_through(&self, from: usize, to: usize) -> bool {
+        self.lines[from + 1..=to]
+            .iter()
+            .all(|line| line.labels.is_empty() && line.directive.is_none())
+    }
+
+    fn lint(&mut self) {
+        let lines = self.lines;
+        let code: Vec<usize> = (0..lines.len())
+            .filter(|idx| lines[*idx].instruction != Instruction::Noop)
+            .collect();
+
+        // The .word that holds the value of each lis
+        let mut operand_words = HashSet::new();
+        for pair in code.windows(2) {
+            let (lis, next) = (pair[0], pair[1]);
+            if let Instruction::Lis { .. } = lines[lis].instruction {
+                let directives = lines[lis + 1..=next].iter().any(|l| l.directive.is_some());
+                if matches!(lines[next].instruction, Instruction::Word { .. }) && !directives {
+                    operand_words.insert(next);
+                }
+            }
+        }
+        for idx in &code {
+            if let Instruction::Lis { .. } = lines[*idx].instruction {
+                let next = code.iter().find(|other| **other > *idx);
+                if !next.is_some_and(|next| operand_words.contains(next)) {
+                    self.warn(
+                        &lines[*idx],
+                        "lis-without-word",
+                        "lis is not followed by a .word".to_string(),
+                    );
+                }
+            }
+        }
+        let instructions: Vec<usize> = code
+            .iter()
+            .copied()
+            .filter(|idx| !operand_words.contains(idx))
+            .collect();
+
+        for idx in &instructions {
+            if lines[*idx].instruction.destination() == Some(0) {
+                let mnemonic = lines[*idx].instruction.mnemonic();
+                self.warn(
+                    &lines[*idx],
+                    "zero-write",
+                    format!("{mnemonic} writes to $0, which always reads as zero"),
+                );
+            }
+        }
+
+        for (k, idx) in instructions.iter().enumerate() {
+            if !matches!(
+                lines[*idx].instruction,
+                Instruction::Mfhi { .. } | Instruction::Mflo { .. }
+            ) {
+                continue;
+            }
+            for next in instructions[k + 1..].iter().take(2) {
+                let instruction = &lines[*next].instruction;
+                if matches!(
+                    instruction,
+                    Instruction::Mult { .. }
+                        | Instruction::Multu { .. }
+                        | Instruction::Div { .. }
+                        | Instruction::Divu { .. }
+                ) {
+                    self.warn(
+                        &lines[*idx],
+                        "hilo-hazard",
+                        format!(
+                            "{} is within two instructions of the {} on line {}",
+                            lines[*idx].instruction.mnemonic(),
+                            instruction.mnemonic(),
+                            lines[*next].line_number
+                        ),
+                    );
+                    break;
+                }
+                if is_unconditional_jump(instruction) {
+                    break;
+                }
+            }
+        }
+
+        for pair in instructions.windows(2) {
+            let (jump, next) = (pair[0], pair[1]);
+            if is_unconditional_jump(&lines[jump].instruction) && self.falls_through(jump, next) {
+                let message = format!(
+                    "unreachable: follows the unconditional {} on line {} and has no label",
+                    lines[jump].instruction.mnemonic(),
+                    lines[jump].line_number
+                );
+                self.warn(&lines[next], "unreachable", message);
+            }
+        }
+
+        let locations = extract_label_locations(lines);
+        let lis_operands: HashSet<u32> = operand_words
+            .iter()
+            .map(|idx| lines[*idx].address)
+            .collect();
+        for idx in &instructions {
+            let line = &lines[*idx];
+            let (Instruction::Beq { i, .. } | Instruction::Bne { i, .. }) = &line.instruction
+            else {
+                continue;
+            };
+            let (target, name) = match i {
+                Value::Label(label) => match locations.get(label.as_str()) {
+                    Some(addr) => (*addr, source_name(label).to_string()),
+                    None => continue,
+                },
+                Value::Literal(offset) => {
+                    let target =
+                        (line.address as i32 + 4 + 4 * (*offset as u16 as i16 as i32)) as u32;
+                    (target, format!("{target:#x}"))
+                }
+            };
+            if lis_operands.contains(&target) {
+                self.warn(
+                    line,
+                    "branch-into-lis",
+                    format!("branch to {name} lands on the .word of a lis"),
+                );
+            }
+        }
+
+        for (k, idx) in instructions.iter().enumerate() {
+            let Instruction::Sw {
+                s: 30,
+                i: Value::Literal(offset),
+                ..
+            } = lines[*idx].instruction
+            else {
+                continue;
+            };
+            if offset as u16 as i16 >= 0 {
+                continue;
+            }
+            let mut previous = *idx;
+            let mut adjusted = false;
+            for next in &instructions[k + 1..] {
+                if !self.falls_through(previous, *next) {
+                    break;
+                }
+                let instruction = &lines[*next].instruction;
+                if instruction.destination() == Some(30) {
+                    adjusted = true;
+                    break;
+                }
+                if is_control_transfer(instruction) {
+                    break;
+                }
+                previous = *next;
+            }
+            if !adjusted {
+                self.warn(
+                    &lines[*idx],
+                    "stack-below-sp",
+                    "sw below $30 is not followed by a stack pointer adjustment".to_string(),
+                );
+            }
+        }
+
+        // Labels before the first instruction of a file are its entry points,
+        // and so are labels after an unconditional jump: nothing falls into
+        // them, so they start routines called from other files
+        let referenced: HashSet<&str> = lines
+            .iter()
+            .filter_map(|line| line.instruction.label())
+            .collect();
+        let mut entry = None;
+        let mut after_jump = false;
+        for line in lines {
+            if entry != Some(line.file) {
+                if line.instruction != Instruction::Noop {
+                    entry = Some(line.file);
+                    after_jump = is_unconditional_jump(&line.instruction);
+                }
+                continue;
+            }
+            let routine = after_jump;
+            if line.instruction != Instruction::Noop {
+                after_jump = is_unconditional_jump(&line.instruction);
+            }
+            if routine {
+                continue;
+            }
+            for label in &line.labels {
+                if !referenced.contains(&label[..label.len() - 1]) {
+                    let message = format!("label {} is never used", source_name(label));
+                    self.warn(line, "unused-label", message);
+                }
+            }
+        }
+    }
+}
+
+// `lint [-D name=value] <files>`: checks the files together, since labels in
+// one can be used by another. Returns false if there were any warnings.
+pub fn run(args: &[String]) -> bool {
+    let mut defines = Defines::new();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let define = match arg.as_str() {
+            "-D" => args.next().map(String::as_str),
+            other if other.starts_with("-D") => Some(&other[2..]),
+            other if other.starts_with('-') => crate::usage(),
+            other => {
+                paths.push(other);
+                continue;
+            }
+        };
+        let (name, value) = define
+            .and_then(parse_define)
+            .unwrap_or_else(|| crate::usage());
+        defines.insert(name, value);
+    }
+    if paths.is_empty() {
+        crate::usage();
+    }
+
+    let mut lines = Vec::new();
+    for (file, path) in paths.iter().enumerate() {
+        let text = fs::read_to_string(path).expect("Could not open MIPS file");
+        lines.extend(parse_lines(io::Cursor::new(text).lines(), file, &defines));
+    }
+    sections::layout(&mut lines, SectionBases::default(), &paths);
+
+    let mut linter = Linter {
+        lines: &lines,
+        source_names: &paths,
+        warnings: Vec::new(),
+    };
+    linter.lint();
+    let mut warnings = linter.warnings;
+    warnings.sort();
+    for (_, _, message) in &warnings {
+        eprintln!("{message}");
+    }
+    match warnings.len() {
+        0 => {}
+        1 => eprintln!("Lint: 1 warning"),
+        count => eprintln!("Lint: {count} warnings"),
+    }
+    warnings.is_empty()
+}
@@ -1,11 +1,56 @@
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, Read, Write};
-use std::path::Path;
-use std::{env, process, u8};
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::{env, fs, process};
+
+mod bench;
+mod cache;
+mod callcheck;
+mod cfg;
+mod conditionals;
+mod coverage;
+mod differential;
+mod formatter;
+mod heapcheck;
+mod jit;
+mod labels;
+mod lint;
+mod mars;
+mod mmio;
+mod optimizer;
+mod output;
+mod pipeline;
+mod predecode;
+mod profiler;
+mod registers;
+mod relax;
+mod sanitizer;
+mod sections;
+mod snapshot;
+mod stats;
+mod symbols;
+mod syscall;
+
+use cache::{Cache, CacheConfig};
+use callcheck::CallChecker;
+use cfg::{Analysis, Cfg};
+use conditionals::{parse_define, Conditionals, Defines};
+use coverage::Coverage;
+use heapcheck::HeapChecker;
+use mmio::{MmioBus, MmioResult, OutputMode, SharedBuffer, StdinDevice, StdoutDevice};
+use output::OutputFormat;
+use pipeline::{PipelineConfig, PipelineModel};
+use predecode::{BlockCache, Engine};
+use profiler::Profiler;
+use registers::{Aliases, RegisterNames};
+use sanitizer::Sanitizer;
+use sections::{Directive, Section, SectionBases, SectionRange, Segment};
+use stats::ExecutionStats;
+use symbols::SymbolTable;
 
 #[derive(Debug, Clone, PartialEq)]
 enum Value {
@@ -104,6 +149,7 @@ enum Instruction {
     Jalr {
         s: u8,
     },
+    Syscall,
     Word {
         i: Value,
     },
@@ -113,27 +159,7 @@ enum Instruction {
 
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            Instruction::Add { d, s, t } => write!(f, "add ${d}, ${s}, ${t}"),
-            Instruction::Sub { d, s, t } => write!(f, "sub ${d}, ${s}, ${t}"),
-            Instruction::Slt { d, s, t } => write!(f, "slt ${d}, ${s}, ${t}"),
-            Instruction::Sltu { d, s, t } => write!(f, "sltu ${d}, ${s}, ${t}"),
-            Instruction::Mult { s, t } => write!(f, "mult ${s}, ${t}"),
-            Instruction::Multu { s, t } => write!(f, "multu ${s}, ${t}"),
-            Instruction::Div { s, t } => write!(f, "div ${s}, ${t}"),
-            Instruction::Divu { s, t } => write!(f, "divu ${s}, ${t}"),
-            Instruction::Mfhi { d } => write!(f, "mfhi ${d}"),
-            Instruction::Mflo { d } => write!(f, "mflo ${d}"),
-            Instruction::Lis { d } => write!(f, "lis ${d}"),
-            Instruction::Lw { t, ref i, s } => write!(f, "lw ${t}, {i}(${s})"),
-            Instruction::Sw { t, ref i, s } => write!(f, "sw ${t}, {i}(${s})"),
-            Instruction::Beq { s, t, ref i } => write!(f, "beq ${s}, ${t}, {i}"),
-            Instruction::Bne { s, t, ref i } => write!(f, "bne ${s}, ${t}, {i}"),
-            Instruction::Jr { s } => write!(f, "jr ${s}"),
-            Instruction::Jalr { s } => write!(f, "jalr ${s}"),
-            Instruction::Word { ref i } => write!(f, ".word {i}"),
-            Instruction::Noop => write!(f, ""),
-        }
+        write!(f, "{}", self.format(RegisterNames::Numeric))
     }
 }
 
@@ -145,6 +171,146 @@ fn sti_word(opcode: u8, s: u8, t: u8, i: u32) -> u32 {
 }
 
 impl Instruction {
+    fn format(&self, names: RegisterNames) -> String {
+        let r = |reg: u8| names.name(reg);
+        match *self {
+            Instruction::Add { d, s, t } => format!("add {}, {}, {}", r(d), r(s), r(t)),
+            Instruction::Sub { d, s, t } => format!("sub {}, {}, {}", r(d), r(s), r(t)),
+            Instruction::Slt { d, s, t } => format!("slt {}, {}, {}", r(d), r(s), r(t)),
+            Instruction::Sltu { d, s, t } => format!("sltu {}, {}, {}", r(d), r(s), r(t)),
+            Instruction::Mult { s, t } => format!("mult {}, {}", r(s), r(t)),
+            Instruction::Multu { s, t } => format!("multu {}, {}", r(s), r(t)),
+            Instruction::Div { s, t } => format!("div {}, {}", r(s), r(t)),
+            Instruction::Divu { s, t } => format!("divu {}, {}", r(s), r(t)),
+            Instruction::Mfhi { d } => format!("mfhi {}", r(d)),
+            Instruction::Mflo { d } => format!("mflo {}", r(d)),
+            Instruction::Lis { d } => format!("lis {}", r(d)),
+            Instruction::Lw { t, ref i, s } => format!("lw {}, {i}({})", r(t), r(s)),
+            Instruction::Sw { t, ref i, s } => format!("sw {}, {i}({})", r(t), r(s)),
+            Instruction::Beq { s, t, ref i } => format!("beq {}, {}, {i}", r(s), r(t)),
+            Instruction::Bne { s, t, ref i } => format!("bne {}, {}, {i}", r(s), r(t)),
+            Instruction::Jr { s } => format!("jr {}", r(s)),
+            Instruction::Jalr { s } => format!("jalr {}", r(s)),
+            Instruction::Syscall => String::from("syscall"),
+            Instruction::Word { ref i } => format!(".word {i}"),
+            Instruction::Noop => String::new(),
+        }
+    }
+
+    fn mnemonic(&self) -> &'static str {
+        match *self {
+            Instruction::Add { .. } => "add",
+            Instruction::Sub { .. } => "sub",
+            Instruction::Slt { .. } => "slt",
+            Instruction::Sltu { .. } => "sltu",
+            Instruction::Mult { .. } => "mult",
+            Instruction::Multu { .. } => "multu",
+            Instruction::Div { .. } => "div",
+            Instruction::Divu { .. } => "divu",
+            Instruction::Mfhi { .. } => "mfhi",
+            Instruction::Mflo { .. } => "mflo",
+            Instruction::Lis { .. } => "lis",
+            Instruction::Lw { .. } => "lw",
+            Instruction::Sw { .. } => "sw",
+            Instruction::Beq { .. } => "beq",
+            Instruction::Bne { .. } => "bne",
+            Instruction::Jr { .. } => "jr",
+            Instruction::Jalr { .. } => "jalr",
+            Instruction::Syscall => "syscall",
+            Instruction::Word { .. } => ".word",
+            Instruction::Noop => "",
+        }
+    }
+
+    // Registers read by the instruction, not counting hi and lo
+    fn sources(&self) -> Vec<u8> {
+        match *self {
+            Instruction::Add { s, t, .. }
+            | Instruction::Sub { s, t, .. }
+            | Instruction::Slt { s, t, .. }
+            | Instruction::Sltu { s, t, .. }
+            | Instruction::Mult { s, t }
+            | Instruction::Multu { s, t }
+            | Instruction::Div { s, t }
+            | Instruction::Divu { s, t }
+            | Instruction::Sw { s, t, .. }
+            | Instruction::Beq { s, t, .. }
+            | Instruction::Bne { s, t, .. } => vec![s, t],
+            Instruction::Lw { s, .. } | Instruction::Jr { s } | Instruction::Jalr { s } => vec![s],
+            // Service number in $2, argument in $4
+            Instruction::Syscall => vec![2, 4],
+            _ => Vec::new(),
+        }
+    }
+
+    // The label used as an immediate, if any
+    fn label(&self) -> Option<&str> {
+        match self {
+            Instruction::Lw {
+                i: Value::Label(label),
+                ..
+            }
+            | Instruction::Sw {
+                i: Value::Label(label),
+                ..
+            }
+            | Instruction::Beq {
+                i: Value::Label(label),
+                ..
+            }
+            | Instruction::Bne {
+                i: Value::Label(label),
+                ..
+            }
+            | Instruction::Word {
+                i: Value::Label(label),
+            } => Some(label),
+            _ => None,
+        }
+    }
+
+    fn label_mut(&mut self) -> Option<&mut String> {
+        match *self {
+            Instruction::Lw {
+                i: Value::Label(ref mut label),
+                ..
+            }
+            | Instruction::Sw {
+                i: Value::Label(ref mut label),
+                ..
+            }
+            | Instruction::Beq {
+                i: Value::Label(ref mut label),
+                ..
+            }
+            | Instruction::Bne {
+                i: Value::Label(ref mut label),
+                ..
+            }
+            | Instruction::Word {
+                i: Value::Label(ref mut label),
+            } => Some(label),
+            _ => None,
+        }
+    }
+
+    // Register written by the instruction, not counting hi and lo
+    fn destination(&self) -> Option<u8> {
+        match *self {
+            Instruction::Add { d, .. }
+            | Instruction::Sub { d, .. }
+            | Instruction::Slt { d, .. }
+            | Instruction::Sltu { d, .. }
+            | Instruction::Mfhi { d }
+            | Instruction::Mflo { d }
+            | Instruction::Lis { d } => Some(d),
+            Instruction::Lw { t, .. } => Some(t),
+            Instruction::Jalr { .. } => Some(31),
+            Instruction::Syscall => Some(2),
+            _ => None,
+        }
+    }
+
     fn assemble(&self) -> u32 {
         match *self {
             Instruction::Add { d, s, t } => std_word(s, t, d, 0x20),
@@ -164,6 +330,7 @@ impl Instruction {
             Instruction::Bne { s, t, ref i } => sti_word(0b000101, s, t, i.to_u32()),
             Instruction::Jr { s } => sti_word(0b000000, s, 0, 0b1000),
             Instruction::Jalr { s } => sti_word(0b000000, s, 0, 0b1001),
+            Instruction::Syscall => std_word(0, 0, 0, 0b001100),
             Instruction::Word { ref i } => i.to_u32(),
             _ => unreachable!(),
         }
@@ -175,7 +342,8 @@ impl Instruction {
         let s = ((word >> 21) & 0b11111) as u8;
         let t = ((word >> 16) & 0b11111) as u8;
         let d = ((word >> 11) & 0b11111) as u8;
-        let i = Value::Literal(word & 0xFFFF);
+        // Sign-extended, so offsets display as negative numbers
+        let i = Value::Literal((word & 0xFFFF) as i16 as u32);
         match first_opcode {
             0b100011 => Instruction::Lw { t, i, s },
             0b101011 => Instruction::Sw { t, i, s },
@@ -195,6 +363,7 @@ impl Instruction {
                 0b101011 => Instruction::Sltu { d, s, t },
                 0b001000 => Instruction::Jr { s },
                 0b001001 => Instruction::Jalr { s },
+                0b001100 => Instruction::Syscall,
                 _ => Instruction::Word {
                     i: Value::Literal(word),
                 },
@@ -206,11 +375,16 @@ impl Instruction {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 struct Line {
     text: String,
+    file: usize,
+    line_number: usize,
     labels: Vec<String>,
     instruction: Instruction,
+    directive: Option<Directive>,
+    section: Section,
+    address: u32,
 }
 
 impl fmt::Display for Line {
@@ -222,13 +396,7 @@ impl fmt::Display for Line {
     }
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
-}
+const ALLOC_RUNTIME: &str = include_str!("../alloc.asm");
 
 fn parse_value(value: &str, bits: u8) -> Value {
     let mask: u32 = ((1_u64 << bits) - 1) as u32;
@@ -236,103 +404,104 @@ fn parse_value(value: &str, bits: u8) -> Value {
         Value::Literal(num & mask)
     } else if let Ok(num) = value.parse::<i32>() {
         Value::Literal((num as u32) & mask)
-    } else if let Ok(num) = u32::from_str_radix(&value[2..], 16) {
+    } else if let Some(Ok(num)) = value
+        .strip_prefix("0x")
+        .map(|hex| u32::from_str_radix(hex, 16))
+    {
         Value::Literal(num & mask)
     } else {
         Value::Label(value.to_string())
     }
 }
 
-fn parse_instruction(instruction: String) -> Instruction {
+fn parse_instruction(instruction: String, aliases: &mut Aliases) -> Instruction {
     let tokens: Vec<&str> = instruction
         .split([' ', ',', '(', ')'])
         .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .collect();
+    let reg = |token: &str| aliases.parse(token);
 
     match tokens.first() {
         None => Instruction::Noop,
         Some(&"add") => Instruction::Add {
-            d: tokens[1][1..].parse().unwrap(),
-            s: tokens[2][1..].parse().unwrap(),
-            t: tokens[3][1..].parse().unwrap(),
+            d: reg(tokens[1]),
+            s: reg(tokens[2]),
+            t: reg(tokens[3]),
         },
         Some(&"sub") => Instruction::Sub {
-            d: tokens[1][1..].parse().unwrap(),
-            s: tokens[2][1..].parse().unwrap(),
-            t: tokens[3][1..].parse().unwrap(),
+            d: reg(tokens[1]),
+            s: reg(tokens[2]),
+            t: reg(tokens[3]),
         },
         Some(&"slt") => Instruction::Slt {
-            d: tokens[1][1..].parse().unwrap(),
-            s: tokens[2][1..].parse().unwrap(),
-            t: tokens[3][1..].parse().unwrap(),
+            d: reg(tokens[1]),
+            s: reg(tokens[2]),
+            t: reg(tokens[3]),
         },
         Some(&"sltu") => Instruction::Sltu {
-            d: tokens[1][1..].parse().unwrap(),
-            s: tokens[2][1..].parse().unwrap(),
-            t: tokens[3][1..].parse().unwrap(),
+            d: reg(tokens[1]),
+            s: reg(tokens[2]),
+            t: reg(tokens[3]),
         },
         Some(&"mult") => Instruction::Mult {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
         },
         Some(&"multu") => Instruction::Multu {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
         },
         Some(&"div") => Instruction::Div {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
         },
         Some(&"divu") => Instruction::Divu {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
-        },
-        Some(&"mfhi") => Instruction::Mfhi {
-            d: tokens[1][1..].parse().unwrap(),
-        },
-        Some(&"mflo") => Instruction::Mflo {
-            d: tokens[1][1..].parse().unwrap(),
-        },
-        Some(&"lis") => Instruction::Lis {
-            d: tokens[1][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
         },
+        Some(&"mfhi") => Instruction::Mfhi { d: reg(tokens[1]) },
+        Some(&"mflo") => Instruction::Mflo { d: reg(tokens[1]) },
+        Some(&"lis") => Instruction::Lis { d: reg(tokens[1]) },
         Some(&"lw") => Instruction::Lw {
-            t: tokens[1][1..].parse().unwrap(),
+            t: reg(tokens[1]),
             i: parse_value(tokens[2], 16),
-            s: tokens[3][1..].parse().unwrap(),
+            s: reg(tokens[3]),
         },
         Some(&"sw") => Instruction::Sw {
-            t: tokens[1][1..].parse().unwrap(),
+            t: reg(tokens[1]),
             i: parse_value(tokens[2], 16),
-            s: tokens[3][1..].parse().unwrap(),
+            s: reg(tokens[3]),
         },
         Some(&"beq") => Instruction::Beq {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
             i: parse_value(tokens[3], 16),
         },
         Some(&"bne") => Instruction::Bne {
-            s: tokens[1][1..].parse().unwrap(),
-            t: tokens[2][1..].parse().unwrap(),
+            s: reg(tokens[1]),
+            t: reg(tokens[2]),
             i: parse_value(tokens[3], 16),
         },
-        Some(&"jr") => Instruction::Jr {
-            s: tokens[1][1..].parse().unwrap(),
-        },
-        Some(&"jalr") => Instruction::Jalr {
-            s: tokens[1][1..].parse().unwrap(),
-        },
+        Some(&"jr") => Instruction::Jr { s: reg(tokens[1]) },
+        Some(&"jalr") => Instruction::Jalr { s: reg(tokens[1]) },
+        Some(&"syscall") => Instruction::Syscall,
         Some(&".word") => Instruction::Word {
             i: parse_value(tokens[1], 32),
         },
+        Some(&".reg") => {
+            aliases.declare(&tokens[1..]);
+            Instruction::Noop
+        }
         Some(other) => panic!("Unrecognized instruction opcode: {other}"),
     }
 }
 
-fn parse_line(line: String) -> Line {
+fn parse_line(line: String, line_number: usize, aliases: &mut Aliases) -> Line {
     lazy_static! {
-        static ref LABELS_RE: Regex = Regex::new(r"[a-zA-Z][a-zA-Z0-9]*:").unwrap();
+        // Ordinary labels, .scoped labels and numeric local labels
+        static ref LABEL_RE: Regex =
+            Regex::new(r"^(\.?[a-zA-Z_][a-zA-Z0-9_.]*|[0-9]+)$").unwrap();
     }
     let original_line = line.trim();
     let semicolon_index = line.find(';').unwrap_or(line.len());
@@ -342,35 +511,63 @@ fn parse_line(line: String) -> Line {
     let labels = &line[..last_colon_index].trim();
     let instruction = &line[last_colon_index..].trim();
 
-    let labels: Vec<String> = LABELS_RE
-        .find_iter(labels)
-        .map(|s| s.as_str().to_string())
+    // Everything before the last colon is a list of labels
+    let mut labels: Vec<String> = labels.split(':').map(|s| s.trim().to_string()).collect();
+    labels.pop();
+    for label in labels.iter_mut() {
+        if !LABEL_RE.is_match(label) {
+            panic!("Invalid label \"{label}\" on line {line_number}");
+        }
+        label.push(':');
+    }
+
+    let tokens: Vec<&str> = instruction
+        .split([' ', ','])
+        .filter(|s| !s.is_empty())
         .collect();
+    let directive = Directive::parse(&tokens, line_number);
+    let instruction = match directive {
+        Some(_) => Instruction::Noop,
+        None => parse_instruction(instruction.to_string(), aliases),
+    };
 
     Line {
         text: original_line.to_string(),
+        line_number,
         labels,
-        instruction: parse_instruction(instruction.to_string()),
+        instruction,
+        directive,
+        ..Line::default()
     }
 }
 
-fn parse_lines(lines: io::Lines<io::BufReader<File>>) -> Vec<Line> {
-    lines.flatten().map(parse_line).collect()
+// Register aliases and local labels are private to the file being parsed
+fn parse_lines<B: BufRead>(lines: io::Lines<B>, file: usize, defines: &Defines) -> Vec<Line> {
+    let mut aliases = Aliases::default();
+    let mut conditionals = Conditionals::new(defines);
+    let mut result: Vec<Line> = lines
+        .map_while(Result::ok)
+        .enumerate()
+        .filter(|(idx, line)| conditionals.include(line, idx + 1))
+        .map(|(idx, line)| Line {
+            file,
+            ..parse_line(line, idx + 1, &mut aliases)
+        })
+        .collect();
+    conditionals.finish();
+    labels::resolve_local_labels(&mut result, file);
+    result
 }
 
 fn extract_label_locations(lines: &Vec<Line>) -> HashMap<&str, u32> {
     let mut result = HashMap::new();
-    let mut addr: u32 = 0;
     for line in lines {
         for label in &line.labels {
             let label = &label[..label.len() - 1];
             if result.contains_key(label) {
                 panic!("Duplicate label {label}");
             }
-            result.insert(label, addr);
-        }
-        if line.instruction != Instruction::Noop {
-            addr += 4;
+            result.insert(label, line.address);
         }
     }
     result
@@ -378,10 +575,25 @@ fn extract_label_locations(lines: &Vec<Line>) -> HashMap<&str, u32> {
 
 fn replace_labels(lines: &Vec<Line>, labels: &HashMap<&str, u32>) -> Vec<Line> {
     let mut result = Vec::new();
-    let mut addr: u32 = 0;
     for line in lines {
-        if line.instruction != Instruction::Noop {
-            addr += 4;
+        // Branch offsets are relative to the following instruction
+        let addr = line.address + 4;
+        if let (Some(Directive::Space(bytes)), false) =
+            (line.directive, line.section == Section::Bss)
+        {
+            for offset in (0..bytes).step_by(4) {
+                result.push(Line {
+                    text: line.text.clone(),
+                    file: line.file,
+                    line_number: line.line_number,
+                    instruction: Instruction::Word {
+                        i: Value::Literal(0),
+                    },
+                    section: line.section,
+                    address: line.address + offset,
+                    ..Line::default()
+                });
+            }
         }
         let new_instruction = match &line.instruction {
             Instruction::Lw {
@@ -459,41 +671,224 @@ fn replace_labels(lines: &Vec<Line>, labels: &HashMap<&str, u32>) -> Vec<Line> {
         if new_instruction != Instruction::Noop {
             result.push(Line {
                 text: line.text.clone(),
+                file: line.file,
+                line_number: line.line_number,
                 instruction: new_instruction,
-                labels: Vec::new(),
+                section: line.section,
+                address: line.address,
+                ..Line::default()
             });
         }
     }
     result
 }
 
-fn assemble(instructions: &[Line]) -> Vec<u32> {
-    instructions
+// Maps the address of each resolved line to its file:line
+fn source_locations(lines: &[Line], source_names: &[&str]) -> HashMap<u32, String> {
+    lines
         .iter()
-        .map(|line| line.instruction.assemble())
+        .map(|line| {
+            let location = format!("{}:{}", source_names[line.file], line.line_number);
+            (line.address, location)
+        })
         .collect()
 }
 
+// One segment for each non-empty .text and .data section, in address order.
+// The zero-initialized .bss is `bss`, and nothing in the program lies at or
+// after `end`. Execution begins at the start of .text, `entry`.
+struct Program {
+    segments: Vec<Segment>,
+    bss: Range<u32>,
+    end: u32,
+    entry: u32,
+}
+
+impl Program {
+    // The lowest address the program puts a word at
+    fn start(&self) -> u32 {
+        self.segments
+            .first()
+            .map_or(self.entry, |segment| segment.start)
+    }
+}
+
+fn assemble(instructions: &[Line], sections: &[SectionRange]) -> Program {
+    let mut segments: Vec<Segment> = sections
+        .iter()
+        .filter(|range| range.section != Section::Bss && range.start < range.end)
+        .map(|range| {
+            let mut words = vec![0; (range.end - range.start) as usize / 4];
+            for line in instructions.iter().filter(|line| {
+                line.section == range.section && line.instruction != Instruction::Noop
+            }) {
+                words[(line.address - range.start) as usize / 4] = line.instruction.assemble();
+            }
+            Segment {
+                start: range.start,
+                words,
+            }
+        })
+        .collect();
+    segments.sort_by_key(|segment| segment.start);
+    let bss = sections
+        .iter()
+        .find(|range| range.section == Section::Bss)
+        .map_or(0..0, |range| range.start..range.end);
+    let end = sections.iter().map(|range| range.end).max().unwrap_or(0);
+    Program {
+        segments,
+        bss,
+        end,
+        entry: sections[0].start,
+    }
+}
+
+// Writes <dir>/<function>.dot for every function in the control-flow graph
+fn write_cfg(
+    dir: &str,
+    lines: &[Line],
+    symbols: &SymbolTable,
+    address_taken: &HashSet<u32>,
+    names: RegisterNames,
+) {
+    let cfg = Cfg::new(lines, symbols, address_taken);
+    let analysis = Analysis {
+        liveness: cfg.liveness(),
+        reaching: cfg.reaching_definitions(),
+        names,
+    };
+    fs::create_dir_all(dir).expect("Could not create CFG directory");
+    for function in &cfg.functions {
+        let name: String = function
+            .name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' => c,
+                _ => '_',
+            })
+            .collect();
+        let path = format!("{dir}/{name}.dot");
+        fs::write(&path, cfg.to_dot(function, &analysis)).expect("Could not write CFG file");
+    }
+}
+
+// Labels grouped by section, in address order
+fn symbol_map(lines: &[Line], sections: &[SectionRange]) -> String {
+    let mut result = String::new();
+    for range in sections {
+        result += &format!(
+            "{:<6} 0x{:08x} - 0x{:08x} ({} bytes)\n",
+            range.section.to_string(),
+            range.start,
+            range.end,
+            range.end - range.start
+        );
+    }
+    let mut symbols: Vec<(u32, Section, &str)> = lines
+        .iter()
+        .flat_map(|line| {
+            line.labels
+                .iter()
+                .map(|label| (line.address, line.section, &label[..label.len() - 1]))
+        })
+        .filter(|(_, _, label)| !labels::is_numeric_local(label))
+        .collect();
+    symbols.sort_by_key(|(addr, _, _)| *addr);
+    result += "\n";
+    for (addr, section, label) in symbols {
+        result += &format!("0x{addr:08x} {:<6} {label}\n", section.to_string());
+    }
+    result
+}
+
+// One line per word; the word after a lis is shown as data
+fn disassemble(machine_code: &[u32], names: RegisterNames) -> String {
+    let mut result = String::new();
+    let mut after_lis = false;
+    for (idx, word) in machine_code.iter().enumerate() {
+        let text = if after_lis {
+            format!(".word 0x{word:08x}")
+        } else {
+            Instruction::disassemble(*word).format(names)
+        };
+        after_lis = text.starts_with("lis ");
+        result += &format!("0x{:08x}: {word:08x}    {text}\n", idx * 4);
+    }
+    result
+}
+
 struct MipsEmulator {
     memory: HashMap<u32, u32>,
     registers: [u32; 32],
     lo: u32,
     hi: u32,
     pc: u32,
+    stats: Option<ExecutionStats>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    pipeline: Option<PipelineModel>,
+    delay_slots: bool,
+    delay_slot_target: Option<u32>,
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    devices: MmioBus,
+    exit_status: Option<u32>,
+    syscalls: bool,
+    heap_break: u32,
+    heap_checker: Option<HeapChecker>,
+    sanitizer: Option<Sanitizer>,
+    call_checker: Option<CallChecker>,
+    steps: u64,
+    snapshot_path: Option<String>,
+    snapshot_interval: Option<u64>,
+    trace: bool,
+    engine: Engine,
+    blocks: BlockCache,
+    register_names: RegisterNames,
 }
 
 impl MipsEmulator {
-    fn new(program: &[u32]) -> MipsEmulator {
+    fn new(program: &Program) -> MipsEmulator {
         let mut result = MipsEmulator {
             memory: HashMap::new(),
             registers: [0; 32],
             lo: 0,
             hi: 0,
-            pc: 0,
+            pc: program.entry,
+            stats: None,
+            profiler: None,
+            coverage: None,
+            pipeline: None,
+            delay_slots: false,
+            delay_slot_target: None,
+            icache: None,
+            dcache: None,
+            devices: MmioBus::with_console(StdinDevice::default(), StdoutDevice::default()),
+            exit_status: None,
+            syscalls: false,
+            // sbrk hands out memory starting at the next page after the program
+            heap_break: (program.end + 0xFFF) & !0xFFF,
+            heap_checker: None,
+            sanitizer: None,
+            call_checker: None,
+            steps: 0,
+            snapshot_path: None,
+            snapshot_interval: None,
+            trace: false,
+            engine: Engine::Predecode,
+            blocks: BlockCache::default(),
+            register_names: RegisterNames::Numeric,
         };
 
-        for (idx, word) in program.iter().enumerate() {
-            result.memory.insert(idx as u32, *word);
+        for segment in &program.segments {
+            let start = segment.start / 4;
+            for (idx, word) in segment.words.iter().enumerate() {
+                result.memory.insert(start + idx as u32, *word);
+            }
+        }
+        for idx in program.bss.start / 4..program.bss.end / 4 {
+            result.memory.insert(idx, 0);
         }
 
         result.registers[30] = 0x100000; // Setup stack pointer
@@ -502,59 +897,154 @@ impl MipsEmulator {
     }
 
     fn dump(&self) {
+        let names = self.register_names;
+        let width = match names {
+            RegisterNames::Numeric => 3,
+            RegisterNames::Symbolic => 5,
+        };
         println!();
         for group in 0..8 {
             for idx in 4 * group..4 * (group + 1) {
                 let register = self.registers[idx];
-                print!("${idx:02} : 0x{register:08x}    ");
+                let name = match names {
+                    RegisterNames::Numeric => format!("${idx:02}"),
+                    RegisterNames::Symbolic => names.name(idx as u8),
+                };
+                print!("{name:>width$} : 0x{register:08x}    ");
             }
             println!();
         }
+        // lo and pc are padded one column less, after five spaces instead of
+        // four, so their colons line up with the register columns
+        let inner = width - 1;
         println!(
-            " hi : 0x{:08x}     lo : 0x{:08x}     pc : 0x{:08x}",
-            self.hi, self.lo, self.pc
+            "{:>width$} : 0x{:08x}     {:>inner$} : 0x{:08x}     {:>inner$} : 0x{:08x}",
+            "hi", self.hi, "lo", self.lo, "pc", self.pc
         );
     }
 
-    fn read(&self, addr: u32) -> u32 {
+    fn save_snapshot_or_exit(&self) {
+        if let Some(path) = self.snapshot_path.as_deref() {
+            if let Err(err) = self.save_snapshot(path) {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    // Stops the machine on a fatal error, keeping a snapshot for inspection
+    fn crash(&mut self, message: String) -> ! {
+        self.devices.flush();
+        self.dump();
+        if let Some(path) = self.snapshot_path.as_deref() {
+            match self.save_snapshot(path) {
+                Ok(()) => eprintln!("Saved crash snapshot to {path}"),
+                Err(err) => eprintln!("{err}"),
+            }
+        }
+        panic!("{message}");
+    }
+
+    fn read(&mut self, addr: u32) -> u32 {
         // eprintln!("Read from {addr:08x}");
-        if addr == 0xffff0004 {
-            let mut buffer = [0; 1];
-            let mut handle = io::stdin().take(1);
-            let next_byte = handle.read(&mut buffer).unwrap_or(0xFF);
-            return next_byte as u32;
+        if let Some(val) = self.devices.read(addr) {
+            if let Some(stats) = self.stats.as_mut() {
+                stats.mmio_reads += 1;
+            }
+            return val;
         }
         match self.memory.get(&(addr / 4)) {
             Some(word) => *word,
-            None => {
-                self.dump();
-                panic!("Reading from uninitialized memory at address {}", self.pc)
-            }
+            None => self.crash(format!(
+                "Reading from uninitialized memory at address {}",
+                self.pc
+            )),
         }
     }
 
     fn write(&mut self, addr: u32, val: u32) {
         // eprintln!("Write value {val} to {addr:08x}");
-        if addr == 0xffff000c {
-            let byte = (val & 0xFF) as u8;
-            let buffer = [byte; 1];
-            io::stdout().write_all(&buffer).expect("Could not write");
+        if let Some(result) = self.devices.write(addr, val) {
+            if let Some(stats) = self.stats.as_mut() {
+                stats.mmio_writes += 1;
+            }
+            if let MmioResult::Halt(status) = result {
+                self.exit_status = Some(status);
+            }
             return;
         }
+        self.blocks.invalidate(addr / 4);
         self.memory.insert(addr / 4, val);
     }
 
+    fn record_data_access(&mut self, addr: u32, is_write: bool, pc: u32) {
+        if let Some(heap_checker) = self.heap_checker.as_mut() {
+            heap_checker.record_access(addr, pc, is_write);
+        }
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            if is_write {
+                sanitizer.check_write(addr, pc);
+            } else {
+                sanitizer.check_read(addr, pc);
+            }
+        }
+        // Memory-mapped I/O is uncached
+        if self.devices.contains(addr) {
+            return;
+        }
+        if let Some(dcache) = self.dcache.as_mut() {
+            dcache.access(addr, is_write, pc);
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.pc == 0x8123456c || self.exit_status.is_some()
+    }
+
     fn step(&mut self) -> bool {
-        if self.pc == 0x8123456c {
+        if self.halted() {
             return false;
         }
+        self.devices.tick();
+        self.steps += 1;
 
         // Fetch
+        let instruction_address = self.pc;
+        let pending_target = self.delay_slot_target.take();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record_instruction(self.pc);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record_instruction(self.pc);
+        }
+        if let Some(icache) = self.icache.as_mut() {
+            icache.access(self.pc, false, self.pc);
+        }
+        if let Some(heap_checker) = self.heap_checker.as_mut() {
+            heap_checker.record_instruction(self.pc, self.registers[3]);
+        }
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            sanitizer.record_instruction();
+        }
         let word = self.read(self.pc);
         let instruction = Instruction::disassemble(word);
         self.pc += 4;
 
-        eprintln!("pc = {}: {instruction}", self.pc);
+        if self.trace {
+            eprintln!(
+                "pc = {}: {}",
+                self.pc,
+                instruction.format(self.register_names)
+            );
+        }
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record_instruction(instruction.mnemonic());
+            match instruction {
+                Instruction::Lw { .. } => stats.loads += 1,
+                Instruction::Sw { .. } => stats.stores += 1,
+                _ => {}
+            }
+        }
 
         // Execute
         match instruction {
@@ -611,6 +1101,9 @@ impl MipsEmulator {
             Instruction::Mfhi { d } => self.registers[d as usize] = self.hi,
             Instruction::Mflo { d } => self.registers[d as usize] = self.lo,
             Instruction::Lis { d } => {
+                if let Some(icache) = self.icache.as_mut() {
+                    icache.access(self.pc, false, instruction_address);
+                }
                 self.registers[d as usize] = self.read(self.pc);
                 self.pc += 4
             }
@@ -619,6 +1112,7 @@ impl MipsEmulator {
                     let i = (*i as i16) as i32;
                     let s = self.registers[s as usize] as i32;
                     let addr = (s + i) as u32;
+                    self.record_data_access(addr, false, instruction_address);
                     self.registers[t as usize] = self.read(addr);
                 } else {
                     unreachable!()
@@ -629,6 +1123,7 @@ impl MipsEmulator {
                     let i = (*i as i16) as i32;
                     let s = self.registers[s as usize] as i32;
                     let addr = (s + i) as u32;
+                    self.record_data_access(addr, true, instruction_address);
                     self.write(addr, self.registers[t as usize]);
                 } else {
                     unreachable!()
@@ -637,9 +1132,16 @@ impl MipsEmulator {
             Instruction::Beq { s, t, ref i } => {
                 if let Value::Literal(ref i) = i {
                     let i = (*i as i16) as i32;
-                    if s == t || self.registers[s as usize] == self.registers[t as usize] {
+                    let taken = s == t || self.registers[s as usize] == self.registers[t as usize];
+                    if taken {
                         self.pc = ((self.pc as i32) + 4 * i) as u32;
                     }
+                    if let Some(stats) = self.stats.as_mut() {
+                        stats.record_branch(taken);
+                    }
+                    if let Some(coverage) = self.coverage.as_mut() {
+                        coverage.record_branch(instruction_address, taken);
+                    }
                 } else {
                     unreachable!()
                 }
@@ -647,26 +1149,108 @@ impl MipsEmulator {
             Instruction::Bne { s, t, ref i } => {
                 if let Value::Literal(ref i) = i {
                     let i = (*i as i16) as i32;
-                    if s != t && self.registers[s as usize] != self.registers[t as usize] {
+                    let taken = s != t && self.registers[s as usize] != self.registers[t as usize];
+                    if taken {
                         self.pc = ((self.pc as i32) + 4 * i) as u32;
                     }
+                    if let Some(stats) = self.stats.as_mut() {
+                        stats.record_branch(taken);
+                    }
+                    if let Some(coverage) = self.coverage.as_mut() {
+                        coverage.record_branch(instruction_address, taken);
+                    }
                 } else {
                     unreachable!()
                 }
             }
-            Instruction::Jr { s } => self.pc = self.registers[s as usize],
+            Instruction::Jr { s } => {
+                self.pc = self.registers[s as usize];
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.record_jump(self.pc);
+                }
+                if let Some(sanitizer) = self.sanitizer.as_mut() {
+                    sanitizer.record_jump(self.pc);
+                }
+                if let Some(call_checker) = self.call_checker.as_mut() {
+                    if s == 31 {
+                        call_checker.record_return(self.pc, &self.registers);
+                    }
+                }
+            }
+            Instruction::Syscall if self.syscalls => self.syscall(),
             Instruction::Jalr { s } => {
                 let temp = self.registers[s as usize];
-                self.registers[31] = self.pc;
+                // With delay slots, return past the instruction in the slot
+                self.registers[31] = if self.delay_slots {
+                    self.pc + 4
+                } else {
+                    self.pc
+                };
                 self.pc = temp;
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.record_call(self.pc, self.registers[31]);
+                }
+                if let Some(heap_checker) = self.heap_checker.as_mut() {
+                    heap_checker.record_call(self.pc, self.registers[31], self.registers[1]);
+                }
+                if let Some(sanitizer) = self.sanitizer.as_mut() {
+                    sanitizer.record_call(self.registers[31]);
+                }
+                if let Some(call_checker) = self.call_checker.as_mut() {
+                    call_checker.record_call(self.pc, self.registers[31], &self.registers);
+                }
+            }
+            _ => self.crash(format!("Unexpected instruction {word} at addr {}", self.pc)),
+        }
+
+        let fallthrough = match instruction {
+            Instruction::Lis { .. } => instruction_address + 8,
+            _ => instruction_address + 4,
+        };
+        let branched = self.pc != fallthrough;
+        if let (Some(call_checker), Some(reg)) =
+            (self.call_checker.as_mut(), instruction.destination())
+        {
+            call_checker.record_write(reg, instruction_address);
+        }
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            if instruction.destination() == Some(30) {
+                sanitizer.record_stack_pointer(self.registers[30], instruction_address);
+            }
+        }
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.record(&instruction, branched);
+        }
+        if let Some(target) = pending_target {
+            if branched {
+                self.crash(format!(
+                    "Branch in delay slot at address {instruction_address}"
+                ));
             }
-            _ => panic!("Unexpected instruction {word} at addr {}", self.pc),
+            self.pc = target;
+        } else if self.delay_slots && branched {
+            // The instruction after the branch runs before control transfers
+            self.delay_slot_target = Some(self.pc);
+            self.pc = fallthrough;
         }
         true
     }
 
     fn run(&mut self) {
-        while self.step() {}
+        if self.can_predecode() {
+            while !self.halted() {
+                self.dispatch();
+            }
+        }
+        while self.step() {
+            if let Some(interval) = self.snapshot_interval {
+                if self.steps.is_multiple_of(interval) {
+                    self.save_snapshot_or_exit();
+                }
+            }
+        }
+        self.devices.flush();
+        self.save_snapshot_or_exit();
     }
 }
 
@@ -684,64 +1268,831 @@ fn read_int(prompt: &str) -> u32 {
     panic!("Could not parse integer");
 }
 
-fn emulate_twoints(machine_code: &Vec<u32>) {
-    let mut emulator: MipsEmulator = MipsEmulator::new(machine_code.as_slice());
+fn read_twoints() -> [u32; 2] {
+    [
+        read_int("Enter value for register 1: "),
+        read_int("Enter value for register 2: "),
+    ]
+}
+
+fn load_twoints(emulator: &mut MipsEmulator, values: [u32; 2]) {
+    emulator.registers[1] = values[0];
+    emulator.registers[2] = values[1];
+}
 
-    emulator.registers[1] = read_int("Enter value for register 1: ");
-    emulator.registers[2] = read_int("Enter value for register 2: ");
+fn emulate_twoints(emulator: &mut MipsEmulator) {
+    load_twoints(emulator, read_twoints());
 
     emulator.run();
     emulator.dump();
 }
 
-fn emulate_mipsarray(machine_code: &Vec<u32>) {
-    let mut emulator: MipsEmulator = MipsEmulator::new(machine_code.as_slice());
-
-    let start_address = (machine_code.len() as u32) * 4 + 8;
+fn read_mipsarray() -> Vec<u32> {
     let array_length = read_int("Enter length of array: ");
+    (0..array_length)
+        .map(|idx| read_int(format!("Enter the value of arr[{idx}]: ").as_str()))
+        .collect()
+}
+
+fn load_mipsarray(emulator: &mut MipsEmulator, program: &Program, array: &[u32]) {
+    let start_address = program.end + 8;
     emulator.registers[1] = start_address;
-    emulator.registers[2] = array_length;
+    emulator.registers[2] = array.len() as u32;
 
-    for idx in 0..array_length {
-        let entry = read_int(format!("Enter the value of arr[{idx}]: ").as_str());
-        emulator.write(start_address + 4 * idx, entry);
+    for (idx, entry) in array.iter().enumerate() {
+        emulator.write(start_address + 4 * idx as u32, *entry);
     }
+    let array_end = start_address + 4 * array.len() as u32;
+    emulator.heap_break = emulator.heap_break.max((array_end + 0xFFF) & !0xFFF);
+}
+
+fn emulate_mipsarray(emulator: &mut MipsEmulator, program: &Program) {
+    load_mipsarray(emulator, program, &read_mipsarray());
 
     emulator.run();
     emulator.dump();
 }
 
+// Maps each --device spec, exiting with usage on a bad or overlapping one
+fn attach_devices(emulator: &mut MipsEmulator, specs: &[String]) {
+    for spec in specs {
+        let attached = mmio::parse_device(spec)
+            .and_then(|(addr, size, device)| emulator.devices.register(addr, size, device));
+        if let Err(err) = attached {
+            println!("{err}");
+            usage();
+        }
+    }
+}
+
+// The native toolchain keeps the stack pointer in $30, but MARS programs use
+// $sp, which is $29
+fn setup_stack(emulator: &mut MipsEmulator, dialect: Dialect) {
+    if dialect == Dialect::Mars {
+        emulator.registers[29] = emulator.registers[30];
+    }
+}
+
+// Two emulators with identical inputs and captured output. The array goes
+// after the larger program, so it is at the same address in both.
+fn paired_emulators(
+    programs: [&Program; 2],
+    engines: [Engine; 2],
+    options: &Options,
+) -> [(MipsEmulator, SharedBuffer); 2] {
+    let inputs = match options.emulation_mode {
+        EmulationMode::TwoInts => read_twoints().to_vec(),
+        EmulationMode::MipsArray => read_mipsarray(),
+        EmulationMode::Standalone => Vec::new(),
+    };
+    let last = programs.iter().max_by_key(|program| program.end).unwrap();
+    [0, 1].map(|idx| {
+        let mut emulator = MipsEmulator::new(programs[idx]);
+        setup_stack(&mut emulator, options.dialect);
+        emulator.engine = engines[idx];
+        emulator.syscalls = options.syscalls;
+        let input = options.program_input.clone().unwrap_or_default();
+        let output = SharedBuffer::default();
+        emulator.devices = MmioBus::with_console(
+            StdinDevice::new(Box::new(io::Cursor::new(input))),
+            StdoutDevice::new(Box::new(io::sink()), OutputMode::Buffered).capture(output.clone()),
+        );
+        attach_devices(&mut emulator, &options.devices);
+        match options.emulation_mode {
+            EmulationMode::TwoInts => load_twoints(&mut emulator, [inputs[0], inputs[1]]),
+            EmulationMode::MipsArray => load_mipsarray(&mut emulator, last, &inputs),
+            EmulationMode::Standalone => {}
+        }
+        (emulator, output)
+    })
+}
+
+enum EmulationMode {
+    TwoInts,
+    MipsArray,
+    // Starts the program without inputs, for programs that read their own
+    Standalone,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Dialect {
+    Native,
+    Mars,
+}
+
+struct Options {
+    inputs: Vec<String>,
+    alloc_runtime: bool,
+    heap_check: bool,
+    sanitize: bool,
+    stack_size: u32,
+    callee_saved: Option<Vec<u8>>,
+    snapshot: Option<String>,
+    snapshot_every: Option<u64>,
+    restore: Option<String>,
+    trace: bool,
+    engine: Engine,
+    section_bases: SectionBases,
+    symbols: bool,
+    defines: Defines,
+    scratch_register: Option<u8>,
+    optimize: bool,
+    verify_optimization: bool,
+    cfg: Option<String>,
+    register_names: RegisterNames,
+    disassemble: bool,
+    bench: bool,
+    differential: bool,
+    output: Option<String>,
+    format: Option<OutputFormat>,
+    load_address: Option<u32>,
+    emulation_mode: EmulationMode,
+    dialect: Dialect,
+    stats: bool,
+    stats_json: Option<String>,
+    profile: bool,
+    profile_folded: Option<String>,
+    coverage: Option<String>,
+    lcov: Option<String>,
+    pipeline: Option<PipelineConfig>,
+    delay_slots: bool,
+    icache: Option<CacheConfig>,
+    dcache: Option<CacheConfig>,
+    devices: Vec<String>,
+    program_input: Option<Vec<u8>>,
+    program_output: Option<String>,
+    output_mode: OutputMode,
+    expected_output: Option<String>,
+    syscalls: bool,
+}
+
+fn usage() -> ! {
+    println!("Usage: mips_assembler [options] <file.asm>...");
+    println!("       mips_assembler fmt [--check] <file.asm>...");
+    println!("       mips_assembler lint [-D <name>[=<value>]] <file.asm>...");
+    println!();
+    println!("  --format <fmt>        Write the assembled program instead of emulating it");
+    println!("                        (hex, srec, memh, bin-le, bin-be, c-array)");
+    println!("  --load-address <addr> Load address recorded in the output (default: the");
+    println!("                        lowest address in the image)");
+    println!("  -o <file>             Write output to a file instead of stdout");
+    println!("  --text-base <addr>    Address of the .text section (default 0)");
+    println!("  --data-base <addr>    Address of the .data section (default: after .text)");
+    println!("  --bss-base <addr>     Address of the .bss section (default: after .data)");
+    println!("  --symbols             Print the section layout and every label's address");
+    println!("  --cfg <dir>           Write each function's control-flow graph, annotated");
+    println!("                        with liveness and reaching definitions, as <dir>/<name>.dot");
+    println!("  -D <name>[=<value>]   Define a name for .if/.ifdef (value defaults to 1)");
+    println!("  --scratch-register <reg>");
+    println!("                        Register that out-of-range branches may clobber when");
+    println!("                        they are rewritten into a lis/jr sequence");
+    println!("  --optimize            Remove redundant instructions and thread branch");
+    println!("                        chains, reporting each change to stderr");
+    println!("  --verify-optimization Run the program before and after optimizing and");
+    println!("                        check that the output and result match");
+    println!("  --emulate <mode>      Emulator driver: twoints, array or none (default array)");
+    println!("  --dialect <dialect>   Source syntax: native (default) or mars, which accepts");
+    println!("                        SPIM/MARS programs and implies --syscalls and");
+    println!("                        --emulate none");
+    println!("  --stats               Print execution statistics to stderr after the run");
+    println!("  --stats-json <file>   Write execution statistics as JSON");
+    println!("  --profile             Print a flat profile and call graph to stderr");
+    println!("  --profile-folded <file>");
+    println!("                        Write folded call stacks for flamegraph tools");
+    println!("  --coverage <file>     Write an annotated source coverage report");
+    println!("  --lcov <file>         Write coverage in lcov tracefile format");
+    println!("  --pipeline            Count cycles with a 5-stage pipeline timing model");
+    println!("  --branch-penalty <n>  Cycles lost on taken branches and jumps (default 2)");
+    println!("  --mult-latency <n>    Cycles until mult results reach hi/lo (default 5)");
+    println!("  --div-latency <n>     Cycles until div results reach hi/lo (default 35)");
+    println!("  --delay-slots         Execute the instruction after each branch or jump");
+    println!("  --icache <spec>       Simulate an L1 instruction cache");
+    println!("  --dcache <spec>       Simulate an L1 data cache");
+    println!("                        spec is size:assoc:line[:lru|fifo|random[:wb|wt]]");
+    println!("  --device <name[@addr]>");
+    println!("                        Attach a memory-mapped device: timer (0xffff0010),");
+    println!("                        random (0xffff0018), exit (0xffff001c) or");
+    println!("                        hexdump (0xffff0020)");
+    println!("  --input <file>        Feed the stdin port from a file");
+    println!("  --input-string <text> Feed the stdin port from a string");
+    println!("  --program-output <file>");
+    println!("                        Send bytes written to the stdout port to a file");
+    println!("  --output-mode <mode>  line (default), buffered or unbuffered");
+    println!("  --expect-output <file>");
+    println!("                        Fail unless the program output matches the file");
+    println!("  --syscalls            Enable SPIM/MARS-compatible syscall services");
+    println!("  --runtime alloc       Link the bundled heap allocator (__alloc_init,");
+    println!("                        __alloc_new and __alloc_delete)");
+    println!("  --heap-check          Report double frees, use after free and leaks in");
+    println!("                        programs using the allocator runtime");
+    println!("  --sanitize            Report writes to code, uninitialized stack reads,");
+    println!("                        stack overflow and (with the allocator) heap misuse");
+    println!("  --stack-size <bytes>  Stack size checked by --sanitize (default 0x10000)");
+    println!("  --check-calls         Check that jalr callees preserve registers");
+    println!("  --callee-saved <regs> Registers callees must preserve, such as 16-23,30");
+    println!("                        (default: everything except $3 and $31)");
+    println!("  --snapshot <file>     Save the machine state to a file when the program");
+    println!("                        stops or crashes");
+    println!("  --snapshot-every <n>  Also save the snapshot every n instructions");
+    println!("  --restore <file>      Resume from a saved snapshot instead of prompting");
+    println!("                        for input");
+    println!("  --trace               Print every instruction as it executes");
+    println!("  --register-names <n>  Show registers as numeric ($29) or symbolic ($sp)");
+    println!("                        in traces, register dumps and disassembly");
+    println!("  --disassemble         Treat the inputs as big-endian machine code and");
+    println!("                        print them as assembly");
+    println!("  --engine <engine>     Execution engine: reference (one instruction at a");
+    println!("                        time), predecode (default) or jit");
+    println!("  --bench               Time the program under every engine and compare the");
+    println!("                        results");
+    println!("  --differential        Run the JIT alongside the reference interpreter and");
+    println!("                        stop at the first block where they disagree");
+    process::exit(1);
+}
+
+fn parse_u32_arg(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut emulation_mode = None;
+    let mut options = Options {
+        inputs: Vec::new(),
+        alloc_runtime: false,
+        heap_check: false,
+        sanitize: false,
+        stack_size: 0x10000,
+        callee_saved: None,
+        snapshot: None,
+        snapshot_every: None,
+        restore: None,
+        trace: false,
+        engine: Engine::Predecode,
+        section_bases: SectionBases::default(),
+        symbols: false,
+        defines: Defines::new(),
+        scratch_register: None,
+        optimize: false,
+        verify_optimization: false,
+        cfg: None,
+        register_names: RegisterNames::Numeric,
+        disassemble: false,
+        bench: false,
+        differential: false,
+        output: None,
+        format: None,
+        load_address: None,
+        emulation_mode: EmulationMode::MipsArray,
+        dialect: Dialect::Native,
+        stats: false,
+        stats_json: None,
+        profile: false,
+        profile_folded: None,
+        coverage: None,
+        lcov: None,
+        pipeline: None,
+        delay_slots: false,
+        icache: None,
+        dcache: None,
+        devices: Vec::new(),
+        program_input: None,
+        program_output: None,
+        output_mode: OutputMode::Line,
+        expected_output: None,
+        syscalls: false,
+    };
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--format" => {
+                let name = value();
+                options.format = Some(OutputFormat::from_name(&name).unwrap_or_else(|| {
+                    println!("Unknown output format {name}");
+                    usage()
+                }));
+            }
+            "--text-base" => {
+                options.section_bases.text = parse_u32_arg(&value()).unwrap_or_else(|| usage());
+            }
+            "--data-base" => {
+                options.section_bases.data =
+                    Some(parse_u32_arg(&value()).unwrap_or_else(|| usage()));
+            }
+            "--bss-base" => {
+                options.section_bases.bss =
+                    Some(parse_u32_arg(&value()).unwrap_or_else(|| usage()));
+            }
+            "--symbols" => options.symbols = true,
+            "--optimize" => options.optimize = true,
+            "--verify-optimization" => {
+                options.optimize = true;
+                options.verify_optimization = true;
+            }
+            "--cfg" => options.cfg = Some(value()),
+            "--scratch-register" => {
+                let reg = Aliases::default().parse(&value());
+                if reg == 0 {
+                    usage();
+                }
+                options.scratch_register = Some(reg);
+            }
+            "--load-address" => {
+                let addr = value();
+                options.load_address = Some(parse_u32_arg(&addr).unwrap_or_else(|| usage()));
+            }
+            "-o" => options.output = Some(value()),
+            "--emulate" => {
+                emulation_mode = Some(match value().as_str() {
+                    "twoints" => EmulationMode::TwoInts,
+                    "array" => EmulationMode::MipsArray,
+                    "none" => EmulationMode::Standalone,
+                    _ => usage(),
+                })
+            }
+            "--dialect" => {
+                options.dialect = match value().as_str() {
+                    "native" => Dialect::Native,
+                    "mars" => Dialect::Mars,
+                    _ => usage(),
+                }
+            }
+            "--stats" => options.stats = true,
+            "--stats-json" => options.stats_json = Some(value()),
+            "--profile" => options.profile = true,
+            "--profile-folded" => options.profile_folded = Some(value()),
+            "--coverage" => options.coverage = Some(value()),
+            "--lcov" => options.lcov = Some(value()),
+            "--pipeline" => {
+                options.pipeline.get_or_insert_with(PipelineConfig::default);
+            }
+            "--branch-penalty" => {
+                let penalty = parse_u32_arg(&value()).unwrap_or_else(|| usage());
+                let config = options.pipeline.get_or_insert_with(PipelineConfig::default);
+                config.branch_penalty = penalty as u64;
+            }
+            "--mult-latency" => {
+                let latency = parse_u32_arg(&value()).unwrap_or_else(|| usage());
+                let config = options.pipeline.get_or_insert_with(PipelineConfig::default);
+                config.mult_latency = latency as u64;
+            }
+            "--div-latency" => {
+                let latency = parse_u32_arg(&value()).unwrap_or_else(|| usage());
+                let config = options.pipeline.get_or_insert_with(PipelineConfig::default);
+                config.div_latency = latency as u64;
+            }
+            "--delay-slots" => options.delay_slots = true,
+            "--device" => options.devices.push(value()),
+            "--input" => {
+                let path = value();
+                options.program_input = Some(fs::read(&path).unwrap_or_else(|_| {
+                    println!("Could not read input file {path}");
+                    usage()
+                }));
+            }
+            "--input-string" => options.program_input = Some(value().into_bytes()),
+            "--program-output" => options.program_output = Some(value()),
+            "--output-mode" => {
+                options.output_mode = match value().as_str() {
+                    "line" => OutputMode::Line,
+                    "buffered" => OutputMode::Buffered,
+                    "unbuffered" => OutputMode::Unbuffered,
+                    _ => usage(),
+                }
+            }
+            "--expect-output" => options.expected_output = Some(value()),
+            "--syscalls" => options.syscalls = true,
+            "--runtime" => match value().as_str() {
+                "alloc" => options.alloc_runtime = true,
+                _ => usage(),
+            },
+            "--heap-check" => options.heap_check = true,
+            "--sanitize" => options.sanitize = true,
+            "--check-calls" => {
+                options
+                    .callee_saved
+                    .get_or_insert_with(|| (1..31).filter(|reg| *reg != 3).collect());
+            }
+            "--callee-saved" => {
+                let list = callcheck::parse_register_list(&value()).unwrap_or_else(|err| {
+                    println!("{err}");
+                    usage()
+                });
+                options.callee_saved = Some(list);
+            }
+            "--snapshot" => options.snapshot = Some(value()),
+            "--snapshot-every" => {
+                let interval = parse_u32_arg(&value()).filter(|n| *n > 0);
+                options.snapshot_every = Some(interval.unwrap_or_else(|| usage()) as u64);
+            }
+            "--restore" => options.restore = Some(value()),
+            "--trace" => options.trace = true,
+            "--register-names" => {
+                let names = RegisterNames::from_name(&value());
+                options.register_names = names.unwrap_or_else(|| usage());
+            }
+            "--disassemble" => options.disassemble = true,
+            "--engine" => {
+                options.engine = Engine::from_name(&value()).unwrap_or_else(|| usage());
+            }
+            "--bench" => options.bench = true,
+            "--differential" => options.differential = true,
+            "--stack-size" => {
+                options.stack_size = parse_u32_arg(&value()).unwrap_or_else(|| usage());
+            }
+            "--icache" | "--dcache" => {
+                let config = CacheConfig::parse(&value()).unwrap_or_else(|err| {
+                    println!("{err}");
+                    usage()
+                });
+                if arg == "--icache" {
+                    options.icache = Some(config);
+                } else {
+                    options.dcache = Some(config);
+                }
+            }
+            "-D" => {
+                let (name, value) = parse_define(&value()).unwrap_or_else(|| usage());
+                options.defines.insert(name, value);
+            }
+            other if other.starts_with("-D") => {
+                let (name, value) = parse_define(&other[2..]).unwrap_or_else(|| usage());
+                options.defines.insert(name, value);
+            }
+            other if other.starts_with('-') => usage(),
+            other => options.inputs.push(other.to_string()),
+        }
+    }
+    if options.inputs.is_empty() || (options.snapshot_every.is_some() && options.snapshot.is_none())
+    {
+        usage();
+    }
+    // MARS programs read their own input and stop with syscalls
+    if options.dialect == Dialect::Mars {
+        options.syscalls = true;
+        options.emulation_mode = EmulationMode::Standalone;
+    }
+    if let Some(mode) = emulation_mode {
+        options.emulation_mode = mode;
+    }
+    if options.optimize && options.delay_slots {
+        println!("--optimize moves branches, so it can't be used with --delay-slots");
+        usage();
+    }
+    options
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Pass a MIPS assembly file");
-        process::exit(1);
+    if args.get(1).is_some_and(|arg| arg == "fmt") {
+        process::exit(if formatter::run(&args[2..]) { 0 } else { 1 });
     }
+    if args.get(1).is_some_and(|arg| arg == "lint") {
+        process::exit(if lint::run(&args[2..]) { 0 } else { 1 });
+    }
+    let options = parse_args(&args);
 
-    let mips_file = &args[1];
-    let lines = read_lines(mips_file).expect("Could not open MIPS file");
-    let lines = parse_lines(lines);
+    if options.disassemble {
+        for path in &options.inputs {
+            let bytes = fs::read(path).expect("Could not open machine code file");
+            let words: Vec<u32> = bytes
+                .chunks(4)
+                .map(|chunk| {
+                    let mut word = [0; 4];
+                    word[..chunk.len()].copy_from_slice(chunk);
+                    u32::from_be_bytes(word)
+                })
+                .collect();
+            print!("{}", disassemble(&words, options.register_names));
+        }
+        return;
+    }
+
+    let mut sources: Vec<(String, String)> = Vec::new();
+    for path in &options.inputs {
+        let text = fs::read_to_string(path).expect("Could not open MIPS file");
+        sources.push((path.clone(), text));
+    }
+    if options.alloc_runtime {
+        sources.push((String::from("alloc.asm"), String::from(ALLOC_RUNTIME)));
+    }
+    let mut lines: Vec<Line> = Vec::new();
+    let mut errors = Vec::new();
+    for (file, (name, text)) in sources.iter().enumerate() {
+        let source = io::Cursor::new(text).lines();
+        // The bundled runtime is always native
+        let is_runtime = options.alloc_runtime && file == sources.len() - 1;
+        if options.dialect == Dialect::Native || is_runtime {
+            lines.extend(parse_lines(source, file, &options.defines));
+            continue;
+        }
+        match mars::parse_lines(source, file, &options.defines) {
+            Ok(parsed) => lines.extend(parsed),
+            Err(file_errors) => errors.extend(
+                file_errors
+                    .into_iter()
+                    .map(|(line_number, message)| format!("{name}:{line_number}: {message}")),
+            ),
+        }
+    }
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("{error}");
+        }
+        process::exit(1);
+    }
+    let source_names: Vec<&str> = sources.iter().map(|(name, _)| name.as_str()).collect();
+    let original = options.verify_optimization.then(|| {
+        let mut original = lines.clone();
+        let sections = relax::layout(
+            &mut original,
+            options.section_bases,
+            &source_names,
+            options.scratch_register,
+        );
+        let resolved = replace_labels(&original, &extract_label_locations(&original));
+        (assemble(&resolved, &sections), sections)
+    });
+    if options.optimize {
+        eprint!(
+            "{}",
+            optimizer::optimize(&mut lines, options.section_bases, &source_names)
+        );
+    }
+    // Keeping data where it was when verifying makes the addresses the two
+    // programs compute match
+    let section_bases = match original {
+        Some((_, ref sections)) => SectionBases {
+            data: Some(sections[1].start),
+            bss: Some(sections[2].start),
+            ..options.section_bases
+        },
+        None => options.section_bases,
+    };
+    let sections = relax::layout(
+        &mut lines,
+        section_bases,
+        &source_names,
+        options.scratch_register,
+    );
     let label_locations = extract_label_locations(&lines);
+    let symbols = SymbolTable::new(&lines, &label_locations);
+    let address_taken = cfg::address_taken(&lines, &label_locations);
+    if options.symbols {
+        print!("{}", symbol_map(&lines, &sections));
+        return;
+    }
     let lines = replace_labels(&lines, &label_locations);
-    let machine_code = assemble(&lines);
-
-    // for line in lines {
-    //     let word = line.instruction.assemble();
-    //     let bytes = word.to_be_bytes();
-    //     eprintln!(
-    //         "{:08b} {:08b} {:08b} {:08b} | {}",
-    //         bytes[0], bytes[1], bytes[2], bytes[3], line.text
-    //     );
-    // }
-
-    let mut bytes = Vec::<u8>::new();
-    for word in &machine_code {
-        bytes.extend_from_slice(&word.to_be_bytes())
-    }
-    // io::stdout()
-    //     .write_all(bytes.as_slice())
-    //     .expect("Writing failed");
-
-    // emulate_twoints(&machine_code);
-    emulate_mipsarray(&machine_code);
+    let program = assemble(&lines, &sections);
+
+    if let Some(ref dir) = options.cfg {
+        write_cfg(
+            dir,
+            &lines,
+            &symbols,
+            &address_taken,
+            options.register_names,
+        );
+        return;
+    }
+
+    if let Some(format) = options.format {
+        // Loading elsewhere moves every segment by the same amount
+        let load_address = options.load_address.unwrap_or(program.start());
+        let segments: Vec<Segment> = program
+            .segments
+            .iter()
+            .map(|segment| Segment {
+                start: segment
+                    .start
+                    .wrapping_sub(program.start())
+                    .wrapping_add(load_address),
+                words: segment.words.clone(),
+            })
+            .collect();
+        let bytes = format.encode(&segments, load_address);
+        match options.output {
+            Some(ref path) => fs::write(path, bytes).expect("Could not write output file"),
+            None => io::stdout()
+                .write_all(bytes.as_slice())
+                .expect("Writing failed"),
+        }
+        return;
+    }
+
+    if options.bench {
+        process::exit(if bench::run(&program) { 0 } else { 1 });
+    }
+    if let Some((original, _)) = original {
+        let [before, after] =
+            paired_emulators([&original, &program], [options.engine; 2], &options);
+        process::exit(if optimizer::verify(before, after) {
+            0
+        } else {
+            1
+        });
+    }
+    if options.differential {
+        let [reference, jit] =
+            paired_emulators([&program; 2], [Engine::Reference, Engine::Jit], &options);
+        process::exit(if differential::run(reference, jit) {
+            0
+        } else {
+            1
+        });
+    }
+
+    let mut emulator = MipsEmulator::new(&program);
+    setup_stack(&mut emulator, options.dialect);
+    emulator.trace = options.trace;
+    emulator.engine = options.engine;
+    emulator.register_names = options.register_names;
+    let input = match options.program_input {
+        Some(ref bytes) => StdinDevice::new(Box::new(io::Cursor::new(bytes.clone()))),
+        None => StdinDevice::default(),
+    };
+    let output: Box<dyn Write> = match options.program_output {
+        Some(ref path) => Box::new(File::create(path).expect("Could not create output file")),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = StdoutDevice::new(output, options.output_mode);
+    let captured_output = SharedBuffer::default();
+    if options.expected_output.is_some() {
+        output = output.capture(captured_output.clone());
+    }
+    emulator.devices = MmioBus::with_console(input, output);
+    if options.stats || options.stats_json.is_some() {
+        emulator.stats = Some(ExecutionStats::default());
+    }
+    if options.profile || options.profile_folded.is_some() {
+        let profiler = Profiler::new(symbols.clone(), emulator.pc, emulator.registers[31]);
+        emulator.profiler = Some(profiler);
+    }
+    if options.coverage.is_some() || options.lcov.is_some() {
+        emulator.coverage = Some(Coverage::default());
+    }
+    attach_devices(&mut emulator, &options.devices);
+    emulator.delay_slots = options.delay_slots;
+    emulator.syscalls = options.syscalls;
+    emulator.icache = options
+        .icache
+        .clone()
+        .map(|config| Cache::new("L1 instruction cache", config));
+    emulator.dcache = options
+        .dcache
+        .clone()
+        .map(|config| Cache::new("L1 data cache", config));
+    if let Some(ref preserved) = options.callee_saved {
+        emulator.call_checker = Some(CallChecker::new(
+            symbols.clone(),
+            source_locations(&lines, &source_names),
+            preserved.clone(),
+        ));
+    }
+    if options.sanitize {
+        let mut code = HashSet::new();
+        let mut previous_was_lis = false;
+        for line in &lines {
+            // Words not loaded by a lis are data, as is everything outside .text
+            let is_instruction =
+                previous_was_lis || !matches!(line.instruction, Instruction::Word { .. });
+            if is_instruction && line.section == Section::Text {
+                code.insert(line.address / 4);
+            }
+            previous_was_lis =
+                !previous_was_lis && matches!(line.instruction, Instruction::Lis { .. });
+        }
+        emulator.sanitizer = Some(Sanitizer::new(
+            symbols.clone(),
+            source_locations(&lines, &source_names),
+            code,
+            program.end,
+            emulator.registers[30],
+            options.stack_size,
+        ));
+    }
+    if options.heap_check || (options.sanitize && options.alloc_runtime) {
+        if !options.alloc_runtime {
+            println!("--heap-check needs the allocator linked with --runtime alloc");
+            usage();
+        }
+        let runtime_file = sources.len() - 1;
+        let runtime_code = lines
+            .iter()
+            .filter(|line| line.file == runtime_file && line.section == Section::Text);
+        let runtime_start = runtime_code.clone().map(|line| line.address).min();
+        let runtime_end = runtime_code.map(|line| line.address + 4).max();
+        let runtime = runtime_start.unwrap_or(0)..runtime_end.unwrap_or(0);
+        emulator.heap_checker = HeapChecker::new(symbols.clone(), runtime);
+    }
+    if let Some(ref config) = options.pipeline {
+        let config = PipelineConfig {
+            delay_slots: options.delay_slots,
+            ..config.clone()
+        };
+        emulator.pipeline = Some(PipelineModel::new(config));
+    }
+
+    emulator.snapshot_path = options.snapshot.clone();
+    emulator.snapshot_interval = options.snapshot_every;
+    if let Some(ref path) = options.restore {
+        if let Err(err) = emulator.restore_snapshot(path) {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        emulator.run();
+        emulator.dump();
+    } else {
+        match options.emulation_mode {
+            EmulationMode::TwoInts => emulate_twoints(&mut emulator),
+            EmulationMode::MipsArray => emulate_mipsarray(&mut emulator, &program),
+            EmulationMode::Standalone => {
+                emulator.run();
+                emulator.dump();
+            }
+        }
+    }
+
+    if let Some(ref stats) = emulator.stats {
+        if options.stats {
+            eprint!("{}", stats.summary());
+        }
+        if let Some(ref path) = options.stats_json {
+            fs::write(path, stats.to_json()).expect("Could not write statistics file");
+        }
+    }
+    if let Some(ref profiler) = emulator.profiler {
+        if options.profile {
+            eprint!("{}", profiler.report());
+        }
+        if let Some(ref path) = options.profile_folded {
+            fs::write(path, profiler.folded_stacks()).expect("Could not write profile file");
+        }
+    }
+    if let Some(ref pipeline) = emulator.pipeline {
+        eprint!("{}", pipeline.report());
+    }
+    for cache in [&emulator.icache, &emulator.dcache].into_iter().flatten() {
+        eprint!("{}", cache.report(&symbols));
+    }
+    if let Some(ref coverage) = emulator.coverage {
+        if let Some(ref path) = options.coverage {
+            let mut report = String::new();
+            for (file, (name, text)) in sources.iter().enumerate() {
+                if sources.len() > 1 {
+                    report.push_str(&format!("==> {name} <==\n"));
+                }
+                report.push_str(&coverage.annotated_source(text, &lines, file));
+            }
+            fs::write(path, report).expect("Could not write coverage file");
+        }
+        if let Some(ref path) = options.lcov {
+            let report: String = sources
+                .iter()
+                .enumerate()
+                .map(|(file, (name, _))| coverage.lcov(name, &lines, file))
+                .collect();
+            fs::write(path, report).expect("Could not write coverage file");
+        }
+    }
+    if let Some(ref call_checker) = emulator.call_checker {
+        eprintln!("Calling convention: {} errors", call_checker.errors);
+    }
+    if let Some(ref sanitizer) = emulator.sanitizer {
+        eprintln!("Sanitizer: {} errors", sanitizer.errors);
+    }
+    if let Some(ref heap_checker) = emulator.heap_checker {
+        let leaks = heap_checker.leak_report();
+        for leak in &leaks {
+            eprintln!("heap error: {leak}");
+        }
+        eprintln!(
+            "Heap check: {} errors, {} leaked blocks",
+            heap_checker.errors.len(),
+            leaks.len()
+        );
+    }
+    if let Some(ref path) = options.expected_output {
+        let expected = fs::read(path).expect("Could not read expected output file");
+        let actual = captured_output.contents();
+        if expected != actual {
+            let position = expected
+                .iter()
+                .zip(actual.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or(expected.len().min(actual.len()));
+            eprintln!(
+                "Program output differs from {path} at byte {position} ({} bytes expected, {} written)",
+                expected.len(),
+                actual.len()
+            );
+            process::exit(1);
+        }
+    }
+    if let Some(status) = emulator.exit_status {
+        process::exit(status as i32);
+    }
 }
@@ -0,0 +1,505 @@
+use std::io::{self, BufRead};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::conditionals::{Conditionals, Defines};
+use crate::registers::Aliases;
+use crate::sections::{Directive, Section};
+use crate::{parse_value, Instruction, Line, Value};
+
+// The assembler temporary, which pseudo-instructions clobber as they do in MARS
+const AT: u8 = 1;
+
+// Instructions MARS accepts that have no equivalent here, so they get a
+// clearer error than a typo would
+const UNSUPPORTED: [&str; 52] = [
+    "and", "andi", "or", "ori", "xor", "xori", "nor", "not", "lui", "sll", "srl", "sra", "sllv",
+    "srlv", "srav", "rol", "ror", "lb", "lbu", "lh", "lhu", "sb", "sh", "lwl", "lwr", "swl", "swr",
+    "ll", "sc", "ulw", "usw", "mthi", "mtlo", "movn", "movz", "madd", "maddu", "msub", "msubu",
+    "clo", "clz", "abs", "seq", "sne", "sge", "sgt", "sle", "bltu", "bgtu", "bleu", "bgeu",
+    "break",
+];
+
+// Lowers one line of MARS source onto lines of native instructions
+struct Lowering<'a> {
+    aliases: &'a Aliases,
+    text: String,
+    line_number: usize,
+    labels: Vec<String>,
+    lines: Vec<Line>,
+}
+
+impl Lowering<'_> {
+    fn push(&mut self, instruction: Instruction, directive: Option<Directive>) {
+        self.lines.push(Line {
+            text: self.text.clone(),
+            line_number: self.line_number,
+            // Labels go on the first line the source line turns into
+            labels: std::mem::take(&mut self.labels),
+            instruction,
+            directive,
+            ..Line::default()
+        });
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.push(instruction, None);
+    }
+
+    // lis $d followed by the value to load
+    fn load(&mut self, d: u8, value: Value) {
+        self.emit(Instruction::Lis { d });
+        self.emit(Instruction::Word { i: value });
+    }
+
+    // Packs bytes into big-endian words, padding the last with zeros
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(4) {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.emit(Instruction::Word {
+                i: Value::Literal(u32::from_be_bytes(word)),
+            });
+        }
+    }
+
+    fn register(&self, token: &str) -> Result<u8, String> {
+        self.aliases
+            .lookup(token)
+            .ok_or_else(|| format!("Invalid register {token}"))
+    }
+
+    // A register, or an immediate loaded into $at
+    fn register_or_immediate(&mut self, token: &str) -> Result<u8, String> {
+        if token.starts_with('$') {
+            return self.register(token);
+        }
+        self.load(AT, immediate(token)?);
+        Ok(AT)
+    }
+
+    // offset($s), ($s) or a label, as the base register and offset for lw/sw
+    fn address(&mut self, token: &str) -> Result<(u8, Value), String> {
+        let Some((offset, base)) = token.split_once('(') else {
+            self.load(AT, Value::Label(token.to_string()));
+            return Ok((AT, Value::Literal(0)));
+        };
+        let base = base
+            .strip_suffix(')')
+            .ok_or_else(|| format!("Expected offset($register) but got {token}"))?;
+        let offset = match offset.trim() {
+            "" => Value::Literal(0),
+            offset => parse_value(offset, 16),
+        };
+        Ok((self.register(base.trim())?, offset))
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[&str]) -> Result<(), String> {
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{mnemonic} expects {count} operand(s) but got {}",
+                    operands.len()
+                ))
+            }
+        };
+        let label = |token: &str| parse_value(token, 16);
+        match mnemonic {
+            "add" | "addu" | "addi" | "addiu" | "sub" | "subu" | "subi" | "subiu" | "slt"
+            | "sltu" | "slti" | "sltiu" => {
+                expect(3)?;
+                let d = self.register(operands[0])?;
+                let s = self.register(operands[1])?;
+                let t = self.register_or_immediate(operands[2])?;
+                self.emit(match mnemonic {
+                    "slt" | "slti" => Instruction::Slt { d, s, t },
+                    "sltu" | "sltiu" => Instruction::Sltu { d, s, t },
+                    _ if mnemonic.starts_with("sub") => Instruction::Sub { d, s, t },
+                    _ => Instruction::Add { d, s, t },
+                });
+            }
+            "mult" | "multu" => {
+                expect(2)?;
+                let s = self.register(operands[0])?;
+                let t = self.register(operands[1])?;
+                self.emit(match mnemonic {
+                    "mult" => Instruction::Mult { s, t },
+                    _ => Instruction::Multu { s, t },
+                });
+            }
+            "div" | "divu" if operands.len() == 2 => {
+                let s = self.register(operands[0])?;
+                let t = self.register(operands[1])?;
+                self.emit(match mnemonic {
+                    "div" => Instruction::Div { s, t },
+                    _ => Instruction::Divu { s, t },
+                });
+            }
+            // Three-operand forms that go through hi or lo
+            "mul" | "div" | "divu" | "rem" | "remu" => {
+                expect(3)?;
+                let d = self.register(operands[0])?;
+                let s = self.register(operands[1])?;
+                let t = self.register_or_immediate(operands[2])?;
+                self.emit(match mnemonic {
+                    "mul" => Instruction::Mult { s, t },
+                    "div" | "rem" => Instruction::Div { s, t },
+                    _ => Instruction::Divu { s, t },
+                });
+                self.emit(match mnemonic {
+                    "rem" | "remu" => Instruction::Mfhi { d },
+                    _ => Instruction::Mflo { d },
+                });
+            }
+            "mfhi" | "mflo" => {
+                expect(1)?;
+                let d = self.register(operands[0])?;
+                self.emit(match mnemonic {
+                    "mfhi" => Instruction::Mfhi { d },
+                    _ => Instruction::Mflo { d },
+                });
+            }
+            "move" => {
+                expect(2)?;
+                let d = self.register(operands[0])?;
+                let s = self.register(operands[1])?;
+                self.emit(Instruction::Add { d, s, t: 0 });
+            }
+            "neg" | "negu" => {
+                expect(2)?;
+                let d = self.register(operands[0])?;
+                let t = self.register(operands[1])?;
+                self.emit(Instruction::Sub { d, s: 0, t });
+            }
+            "li" | "la" => {
+                expect(2)?;
+                let d = self.register(operands[0])?;
+                self.load(d, immediate(operands[1])?);
+            }
+            "lw" | "sw" => {
+                expect(2)?;
+                let t = self.register(operands[0])?;
+                let (s, i) = self.address(operands[1])?;
+                self.emit(match mnemonic {
+                    "lw" => Instruction::Lw { t, i, s },
+                    _ => Instruction::Sw { t, i, s },
+                });
+            }
+            "beq" | "bne" => {
+                expect(3)?;
+                let s = self.register(operands[0])?;
+                let t = self.register_or_immediate(operands[1])?;
+                let i = label(operands[2]);
+                self.emit(match mnemonic {
+                    "beq" => Instruction::Beq { s, t, i },
+                    _ => Instruction::Bne { s, t, i },
+                });
+            }
+            "beqz" | "bnez" => {
+                expect(2)?;
+                let s = self.register(operands[0])?;
+                let i = label(operands[1]);
+                self.emit(match mnemonic {
+                    "beqz" => Instruction::Beq { s, t: 0, i },
+                    _ => Instruction::Bne { s, t: 0, i },
+                });
+            }
+            "b" => {
+                expect(1)?;
+                self.emit(Instruction::Beq {
+                    s: 0,
+                    t: 0,
+                    i: label(operands[0]),
+                });
+            }
+            // Comparisons set $at with slt, then branch on it
+            "blt" | "bgt" | "ble" | "bge" | "bltz" | "bgtz" | "blez" | "bgez" => {
+                let (s, t, target) = if mnemonic.ends_with('z') {
+                    expect(2)?;
+                    (self.register(operands[0])?, 0, operands[1])
+                } else {
+                    expect(3)?;
+                    let s = self.register(operands[0])?;
+                    (s, self.register_or_immediate(operands[1])?, operands[2])
+                };
+                let (s, t) = match &mnemonic[..3] {
+                    "blt" | "bge" => (s, t),
+                    _ => (t, s),
+                };
+                self.emit(Instruction::Slt { d: AT, s, t });
+                let i = label(target);
+                self.emit(match &mnemonic[..3] {
+                    "blt" | "bgt" => Instruction::Bne { s: AT, t: 0, i },
+                    _ => Instruction::Beq { s: AT, t: 0, i },
+                });
+            }
+            "j" | "jal" => {
+                expect(1)?;
+                self.load(AT, Value::Label(operands[0].to_string()));
+                self.emit(match mnemonic {
+                    "j" => Instruction::Jr { s: AT },
+                    _ => Instruction::Jalr { s: AT },
+                });
+            }
+            "jr" => {
+                expect(1)?;
+                let s = self.register(operands[0])?;
+                self.emit(Instruction::Jr { s });
+            }
+            "jalr" => {
+                // jalr $ra, $s is the same as jalr $s; other link registers aren't
+                let s = match operands {
+                    [s] => self.register(s)?,
+                    [d, s] if self.register(d)? == 31 => self.register(s)?,
+                    [_, _] => return Err(String::from("jalr can only link through $ra")),
+                    _ => return Err(String::from("jalr expects 1 or 2 operands")),
+                };
+                self.emit(Instruction::Jalr { s });
+            }
+            "syscall" => {
+                expect(0)?;
+                self.emit(Instruction::Syscall);
+            }
+            "nop" => {
+                expect(0)?;
+                self.emit(Instruction::Add { d: 0, s: 0, t: 0 });
+            }
+            other if UNSUPPORTED.contains(&other) || other.contains('.') => {
+                return Err(format!("{other} has no equivalent in this instruction set"));
+            }
+            other => return Err(format!("Unrecognized instruction {other}")),
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, operands: &str) -> Result<(), String> {
+        let values = || split_operands(operands);
+        match name {
+            ".text" | ".data" if !operands.is_empty() => {
+                return Err(format!(
+                    "{name} with an address is not supported; use --{}-base",
+                    &name[1..]
+                ));
+            }
+            ".text" => self.push(Instruction::Noop, Some(Directive::Section(Section::Text))),
+            ".data" => self.push(Instruction::Noop, Some(Directive::Section(Section::Data))),
+            // Every label is already visible to every file
+            ".globl" | ".global" => self.push(Instruction::Noop, None),
+            // Everything is word aligned already
+            ".align" if matches!(operands, "0" | "1" | "2") => self.push(Instruction::Noop, None),
+            ".word" => {
+                let mut words = Vec::new();
+                for value in values() {
+                    // value:count repeats the value
+                    let (value, count) = match value.split_once(':') {
+                        Some((value, count)) => (
+                            value,
+                            count
+                                .trim()
+                                .parse()
+                                .map_err(|_| format!("Invalid repeat count in {value}"))?,
+                        ),
+                        None => (value, 1),
+                    };
+                    let value = immediate(value.trim())?;
+                    words.extend(std::iter::repeat_n(value, count));
+                }
+                for i in words {
+                    self.emit(Instruction::Word { i });
+                }
+            }
+            ".half" | ".byte" => {
+                let mut bytes = Vec::new();
+                for value in values() {
+                    let Value::Literal(value) = immediate(value)? else {
+                        return Err(format!("{name} only takes numbers"));
+                    };
+                    match name {
+                        ".half" => bytes.extend((value as u16).to_be_bytes()),
+                        _ => bytes.push(value as u8),
+                    }
+                }
+                self.emit_bytes(&bytes);
+            }
+            ".ascii" | ".asciiz" => {
+                let mut bytes = Vec::new();
+                for value in values() {
+                    bytes.extend(string_literal(value)?);
+                    if name == ".asciiz" {
+                        bytes.push(0);
+                    }
+                }
+                self.emit_bytes(&bytes);
+            }
+            ".space" => {
+                let bytes: u32 = operands
+                    .parse()
+                    .map_err(|_| format!("Expected .space <bytes> but got .space {operands}"))?;
+                self.push(
+                    Instruction::Noop,
+                    Some(Directive::Space(bytes.next_multiple_of(4))),
+                );
+            }
+            other => return Err(format!("Directive {other} is not supported")),
+        }
+        Ok(())
+    }
+}
+
+// Splits directive operands on commas outside string literals
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (idx, c) in operands.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                result.push(operands[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(operands[start..].trim());
+    result.retain(|operand| !operand.is_empty());
+    result
+}
+
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+fn string_literal(token: &str) -> Result<Vec<u8>, String> {
+    let inner = token
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected a string but got {token}"))?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let next = chars.next().unwrap_or('\\');
+        result.push(escape(next).ok_or_else(|| format!("Unknown escape \\{next} in {token}"))?);
+    }
+    Ok(result.into_bytes())
+}
+
+// A number, a character like 'a' or '\n', or a label
+fn immediate(token: &str) -> Result<Value, String> {
+    if token.starts_with('$') {
+        return Err(format!("Expected a value but got {token}"));
+    }
+    let Some(inner) = token
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+    else {
+        return Ok(parse_value(token, 32));
+    };
+    let mut chars = inner.chars();
+    let c = match (chars.next(), chars.next(), chars.next()) {
+        (Some('\\'), Some(c), None) => escape(c),
+        (Some(c), None, None) => Some(c),
+        _ => None,
+    };
+    c.map(|c| Value::Literal(c as u32))
+        .ok_or_else(|| format!("Invalid character {token}"))
+}
+
+// Cuts a line at the first # outside a string or character literal
+fn strip_comment(line: &str) -> &str {
+    let (mut quote, mut escaped) = (None, false);
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote.is_some() => escaped = true,
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            '#' if quote.is_none() => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Parses a file written for MARS or SPIM. Each line becomes one or more lines
+// of native instructions, keeping its line number. Differences from MARS:
+//
+//   $sp starts out equal to $30, the native stack pointer
+//   memory is big-endian, and each data directive starts on a word boundary
+//   labels are visible to every file, with or without .globl
+//
+// On failure, returns every line that couldn't be lowered with the reason.
+pub fn parse_lines<B: BufRead>(
+    lines: io::Lines<B>,
+    file: usize,
+    defines: &Defines,
+) -> Result<Vec<Line>, Vec<(usize, String)>> {
+    lazy_static! {
+        static ref LABEL_RE: Regex = Regex::new(r"^\s*([a-zA-Z_][a-zA-Z0-9_.]*)\s*:").unwrap();
+    }
+    let aliases = Aliases::default();
+    let mut conditionals = Conditionals::new(defines);
+    let mut result = Vec::new();
+    let mut errors = Vec::new();
+    for (idx, source) in lines.map_while(Result::ok).enumerate() {
+        let line_number = idx + 1;
+        let mut rest = strip_comment(&source);
+        if !conditionals.include(rest, line_number) {
+            continue;
+        }
+        let mut labels = Vec::new();
+        while let Some(captures) = LABEL_RE.captures(rest) {
+            labels.push(format!("{}:", &captures[1]));
+            rest = &rest[captures[0].len()..];
+        }
+        let rest = rest.trim();
+        let mut lowering = Lowering {
+            aliases: &aliases,
+            text: source.trim().to_string(),
+            line_number,
+            labels,
+            lines: Vec::new(),
+        };
+        let (name, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let lowered = if name.is_empty() {
+            Ok(())
+        } else if name.starts_with('.') {
+            lowering.directive(name, operands.trim())
+        } else {
+            let operands: Vec<&str> = operands
+                .split([' ', '\t', ','])
+                .filter(|s| !s.is_empty())
+                .collect();
+            lowering.instruction(name, &operands)
+        };
+        if let Err(message) = lowered {
+            errors.push((line_number, message));
+        }
+        // Keep a line for labels, blank lines and anything that failed
+        if lowering.lines.is_empty() {
+            lowering.push(Instruction::Noop, None);
+        }
+        result.extend(lowering.lines.into_iter().map(|line| Line { file, ..line }));
+    }
+    conditionals.finish();
+    if errors.is_empty() {
+        Ok(result)
+    } else {
+        Err(errors)
+    }
+}
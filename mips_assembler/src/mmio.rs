@@ -0,0 +1,430 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+pub enum MmioResult {
+    Continue,
+    Halt(u32),
+    // Text for the console, which the bus writes to the stdout port
+    Print(String),
+}
+
+// A device answering loads and stores to a range of word addresses. Offsets
+// passed in are relative to the start of the range.
+pub trait MmioDevice {
+    fn name(&self) -> &'static str;
+
+    fn read(&mut self, _offset: u32) -> u32 {
+        0
+    }
+
+    fn write(&mut self, _offset: u32, _val: u32) -> MmioResult {
+        MmioResult::Continue
+    }
+
+    // Called once per executed instruction
+    fn tick(&mut self) {}
+
+    // Same as calling tick `cycles` times
+    fn advance(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    // Called when the machine stops, so buffered output isn't lost
+    fn flush(&mut self) {}
+
+    // Internal state for emulator snapshots; stateless devices save nothing
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore_state(&mut self, _state: &[u8]) {}
+}
+
+struct Mapping {
+    start: u32,
+    end: u32,
+    device: Box<dyn MmioDevice>,
+}
+
+#[derive(Default)]
+pub struct MmioBus {
+    mappings: Vec<Mapping>,
+}
+
+impl MmioBus {
+    pub fn with_console(input: StdinDevice, output: StdoutDevice) -> MmioBus {
+        let mut bus = MmioBus::default();
+        bus.register(STDIN_ADDRESS, 4, Box::new(input)).unwrap();
+        bus.register(STDOUT_ADDRESS, 4, Box::new(output)).unwrap();
+        bus
+    }
+
+    pub fn register(
+        &mut self,
+        start: u32,
+        size: u32,
+        device: Box<dyn MmioDevice>,
+    ) -> Result<(), String> {
+        let Some(end) = start.checked_add(size) else {
+            return Err(format!(
+                "Device {} at 0x{start:08x} runs past the end of memory",
+                device.name()
+            ));
+        };
+        if let Some(other) = self
+            .mappings
+            .iter()
+            .find(|mapping| start < mapping.end && mapping.start < end)
+        {
+            return Err(format!(
+                "Device {} at 0x{start:08x} overlaps device {} at 0x{:08x}",
+                device.name(),
+                other.device.name(),
+                other.start
+            ));
+        }
+        self.mappings.push(Mapping { start, end, device });
+        Ok(())
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        self.mappings
+            .iter()
+            .any(|mapping| mapping.start <= addr && addr < mapping.end)
+    }
+
+    fn find(&mut self, addr: u32) -> Option<&mut Mapping> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| mapping.start <= addr && addr < mapping.end)
+    }
+
+    pub fn read(&mut self, addr: u32) -> Option<u32> {
+        let mapping = self.find(addr)?;
+        Some(mapping.device.read(addr - mapping.start))
+    }
+
+    pub fn write(&mut self, addr: u32, val: u32) -> Option<MmioResult> {
+        let mapping = self.find(addr)?;
+        match mapping.device.write(addr - mapping.start, val) {
+            MmioResult::Print(text) => {
+                for byte in text.bytes() {
+                    self.write(STDOUT_ADDRESS, byte as u32);
+                }
+                Some(MmioResult::Continue)
+            }
+            result => Some(result),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.tick();
+        }
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.advance(cycles);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.flush();
+        }
+    }
+
+    // (start address, device name, state) for every mapped device
+    pub fn save_state(&self) -> Vec<(u32, &'static str, Vec<u8>)> {
+        self.mappings
+            .iter()
+            .map(|mapping| {
+                let device = &mapping.device;
+                (mapping.start, device.name(), device.save_state())
+            })
+            .collect()
+    }
+
+    // Returns false if no device with that name is mapped at that address
+    pub fn restore_state(&mut self, start: u32, name: &str, state: &[u8]) -> bool {
+        match self
+            .mappings
+            .iter_mut()
+            .find(|mapping| mapping.start == start && mapping.device.name() == name)
+        {
+            Some(mapping) => {
+                mapping.device.restore_state(state);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub const STDIN_ADDRESS: u32 = 0xffff0004;
+pub const STDOUT_ADDRESS: u32 = 0xffff000c;
+
+// Value read from the stdin port once input is exhausted
+pub const EOF: u32 = -1_i32 as u32;
+
+pub struct StdinDevice {
+    input: Box<dyn Read>,
+}
+
+impl StdinDevice {
+    pub fn new(input: Box<dyn Read>) -> StdinDevice {
+        StdinDevice { input }
+    }
+}
+
+impl Default for StdinDevice {
+    fn default() -> StdinDevice {
+        StdinDevice::new(Box::new(io::stdin()))
+    }
+}
+
+impl MmioDevice for StdinDevice {
+    fn name(&self) -> &'static str {
+        "stdin"
+    }
+
+    fn read(&mut self, _offset: u32) -> u32 {
+        let mut buffer = [0; 1];
+        loop {
+            return match self.input.read(&mut buffer) {
+                Ok(1) => buffer[0] as u32,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                _ => EOF,
+            };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    // Flushed on every newline, like a terminal
+    Line,
+    // Flushed only when the machine stops
+    Buffered,
+    // Flushed after every byte
+    Unbuffered,
+}
+
+// Collects program output in memory so it can be inspected after the run.
+#[derive(Debug, Default, Clone)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct StdoutDevice {
+    output: Box<dyn Write>,
+    capture: Option<SharedBuffer>,
+    mode: OutputMode,
+}
+
+impl StdoutDevice {
+    pub fn new(output: Box<dyn Write>, mode: OutputMode) -> StdoutDevice {
+        let output: Box<dyn Write> = match mode {
+            OutputMode::Line => Box::new(io::LineWriter::new(output)),
+            OutputMode::Buffered => Box::new(io::BufWriter::new(output)),
+            OutputMode::Unbuffered => output,
+        };
+        StdoutDevice {
+            output,
+            capture: None,
+            mode,
+        }
+    }
+
+    // Also copy everything written into `buffer`
+    pub fn capture(mut self, buffer: SharedBuffer) -> StdoutDevice {
+        self.capture = Some(buffer);
+        self
+    }
+}
+
+impl Default for StdoutDevice {
+    fn default() -> StdoutDevice {
+        StdoutDevice::new(Box::new(io::stdout()), OutputMode::Line)
+    }
+}
+
+impl MmioDevice for StdoutDevice {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn write(&mut self, _offset: u32, val: u32) -> MmioResult {
+        let byte = (val & 0xFF) as u8;
+        let buffer = [byte; 1];
+        self.output.write_all(&buffer).expect("Could not write");
+        if self.mode == OutputMode::Unbuffered {
+            self.output.flush().expect("Could not write");
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.write_all(&buffer).expect("Could not write");
+        }
+        MmioResult::Continue
+    }
+
+    fn flush(&mut self) {
+        self.output.flush().expect("Could not write");
+    }
+}
+
+// Two words: the low and high halves of the number of instructions executed.
+// Writing anything resets the count.
+#[derive(Default)]
+pub struct TimerDevice {
+    cycles: u64,
+}
+
+impl MmioDevice for TimerDevice {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            0 => self.cycles as u32,
+            _ => (self.cycles >> 32) as u32,
+        }
+    }
+
+    fn write(&mut self, _offset: u32, _val: u32) -> MmioResult {
+        self.cycles = 0;
+        MmioResult::Continue
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn advance(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.cycles.to_be_bytes().to_vec()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) {
+        if let Ok(bytes) = state.try_into() {
+            self.cycles = u64::from_be_bytes(bytes);
+        }
+    }
+}
+
+// Reads return pseudo-random words; writing sets the seed.
+pub struct RandomDevice {
+    state: u32,
+}
+
+impl Default for RandomDevice {
+    fn default() -> RandomDevice {
+        RandomDevice { state: 0x2545f491 }
+    }
+}
+
+impl MmioDevice for RandomDevice {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn read(&mut self, _offset: u32) -> u32 {
+        // xorshift32
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    fn write(&mut self, _offset: u32, val: u32) -> MmioResult {
+        // xorshift gets stuck at zero
+        self.state = if val == 0 { 0x2545f491 } else { val };
+        MmioResult::Continue
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.state.to_be_bytes().to_vec()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) {
+        if let Ok(bytes) = state.try_into() {
+            self.state = u32::from_be_bytes(bytes);
+        }
+    }
+}
+
+// Writing a word halts the machine with that word as the exit status.
+pub struct ExitDevice;
+
+impl MmioDevice for ExitDevice {
+    fn name(&self) -> &'static str {
+        "exit"
+    }
+
+    fn write(&mut self, _offset: u32, val: u32) -> MmioResult {
+        MmioResult::Halt(val)
+    }
+}
+
+// Writing a word prints it in hex on its own line, through the console so it
+// is part of the program's output.
+pub struct HexDumpDevice;
+
+impl MmioDevice for HexDumpDevice {
+    fn name(&self) -> &'static str {
+        "hexdump"
+    }
+
+    fn write(&mut self, _offset: u32, val: u32) -> MmioResult {
+        MmioResult::Print(format!("0x{val:08x}\n"))
+    }
+}
+
+// Parses name[@address] from the command line into a device, its address and
+// the size of its range.
+pub fn parse_device(spec: &str) -> Result<(u32, u32, Box<dyn MmioDevice>), String> {
+    let (name, addr) = match spec.split_once('@') {
+        Some((name, addr)) => {
+            let addr = match addr.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => addr.parse(),
+            }
+            .map_err(|_| format!("Invalid device address {addr}"))?;
+            (name, Some(addr))
+        }
+        None => (spec, None),
+    };
+    let (default_addr, size, device): (u32, u32, Box<dyn MmioDevice>) = match name {
+        "timer" => (0xffff0010, 8, Box::<TimerDevice>::default()),
+        "random" => (0xffff0018, 4, Box::<RandomDevice>::default()),
+        "exit" => (0xffff001c, 4, Box::new(ExitDevice)),
+        "hexdump" => (0xffff0020, 4, Box::new(HexDumpDevice)),
+        _ => return Err(format!("Unknown device {name}")),
+    };
+    if addr.unwrap_or(default_addr) % 4 != 0 {
+        return Err(format!("Device {name} must be word-aligned"));
+    }
+    Ok((addr.unwrap_or(default_addr), size, device))
+}
@@ -0,0 +1,589 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::cfg::{self, Cfg, ENTRY};
+use crate::labels::source_name;
+use crate::mmio::SharedBuffer;
+use crate::sections::{self, Section, SectionBases};
+use crate::symbols::SymbolTable;
+use crate::{extract_label_locations, replace_labels, Instruction, Line, MipsEmulator, Value};
+
+enum Edit {
+    // Drops the instruction (and the .word of a lis), leaving its labels on
+    // whatever follows
+    Remove(usize, &'static str),
+    // Points a branch at the end of the chain of jumps it lands on
+    Retarget(usize, String),
+}
+
+// The rewrites in the order they are tried. Each round applies only the first
+// kind that finds anything, since rewrites of different kinds can depend on
+// each other: a dead store may be the lis that makes a later one redundant.
+const PASSES: [fn(&Round) -> Vec<Edit>; 5] = [
+    moves_to_self,
+    branches_to_next,
+    branch_chains,
+    redundant_lis,
+    dead_stores,
+];
+
+// The program as it is laid out in one round
+struct Round<'a> {
+    lines: &'a [Line],
+    labels: HashMap<&'a str, u32>,
+    // Line of each instruction in .text by address
+    by_address: HashMap<u32, usize>,
+    cfg: Cfg,
+}
+
+impl Round<'_> {
+    // The .word holding the value of the lis on line `idx`, if it can be
+    // removed along with it
+    fn lis_operand(&self, idx: usize) -> Option<usize> {
+        let Instruction::Lis { .. } = self.lines[idx].instruction else {
+            return None;
+        };
+        let word = *self.by_address.get(&(self.lines[idx].address + 4))?;
+        let line = &self.lines[word];
+        (matches!(line.instruction, Instruction::Word { .. }) && line.labels.is_empty())
+            .then_some(word)
+    }
+
+    fn removable(&self, idx: usize) -> bool {
+        match self.lines[idx].instruction {
+            Instruction::Lis { .. } => self.lis_operand(idx).is_some(),
+            _ => true,
+        }
+    }
+}
+
+fn branch_label(instruction: &Instruction) -> Option<&str> {
+    match instruction {
+        Instruction::Beq {
+            i: Value::Label(label),
+            ..
+        }
+        | Instruction::Bne {
+            i: Value::Label(label),
+            ..
+        } => Some(label),
+        _ => None,
+    }
+}
+
+// add $x, $x, $0 and the like
+fn moves_to_self(round: &Round) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for idx in round.by_address.values() {
+        let is_move = match round.lines[*idx].instruction {
+            Instruction::Add { d, s, t } => (s == d && t == 0) || (s == 0 && t == d),
+            Instruction::Sub { d, s, t } => s == d && t == 0,
+            _ => false,
+        };
+        if is_move {
+            edits.push(Edit::Remove(*idx, "move to itself"));
+        }
+    }
+    edits
+}
+
+fn branches_to_next(round: &Round) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for idx in round.by_address.values() {
+        let line = &round.lines[*idx];
+        let Some(label) = branch_label(&line.instruction) else {
+            continue;
+        };
+        if round.labels.get(label) == Some(&(line.address + 4)) {
+            edits.push(Edit::Remove(*idx, "branch to the next instruction"));
+        }
+    }
+    edits
+}
+
+// Where a branch to `label` ends up after following unconditional branches,
+// or None if they loop forever
+fn final_target<'a>(round: &Round<'a>, label: &'a str) -> Option<&'a str> {
+    let mut label = label;
+    let mut seen = HashSet::new();
+    loop {
+        let addr = *round.labels.get(label)?;
+        if !seen.insert(addr) {
+            return None;
+        }
+        let Some(idx) = round.by_address.get(&addr) else {
+            return Some(label);
+        };
+        let instruction = &round.lines[*idx].instruction;
+        match (instruction, branch_label(instruction)) {
+            (Instruction::Beq { s, t, .. }, Some(next)) if s == t => label = next,
+            _ => return Some(label),
+        }
+    }
+}
+
+fn branch_chains(round: &Round) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for idx in round.by_address.values() {
+        let line = &round.lines[*idx];
+        let Some(label) = branch_label(&line.instruction) else {
+            continue;
+        };
+        let Some(target) = final_target(round, label) else {
+            continue;
+        };
+        if round.labels[target] == round.labels[label] {
+            continue;
+        }
+        // Later removals only bring the target closer
+        let offset = (round.labels[target] as i64 - (line.address as i64 + 4)) / 4;
+        if (i16::MIN as i64..=i16::MAX as i64).contains(&offset) {
+            edits.push(Edit::Retarget(*idx, target.to_string()));
+        }
+    }
+    edits
+}
+
+// A lis is redundant when every definition of its register reaching it is a
+// lis of the same value. Values are compared as written, so a label and a
+// number that happen to be equal now don't count.
+fn redundant_lis(round: &Round) -> Vec<Edit> {
+    let reaching = round.cfg.reaching_definitions();
+    let lis_values: HashMap<u32, (&Instruction, &Instruction)> = round
+        .by_address
+        .values()
+        .filter_map(|idx| {
+            let word = round.lis_operand(*idx)?;
+            let lis = &round.lines[*idx];
+            Some((
+                lis.address,
+                (&lis.instruction, &round.lines[word].instruction),
+            ))
+        })
+        .collect();
+
+    // Each redundant lis and the ones that make it so
+    let mut candidates = BTreeMap::new();
+    for (block_idx, block) in round.cfg.blocks.iter().enumerate() {
+        for (index, op) in block.ops.iter().enumerate() {
+            let Some(value) = lis_values.get(&op.address) else {
+                continue;
+            };
+            let Instruction::Lis { d } = op.instruction else {
+                continue;
+            };
+            // Around a loop a lis can reach itself, which is no reason to keep it
+            let sources: Vec<u32> = reaching
+                .reaching_before(&round.cfg, block_idx, index)
+                .iter()
+                .filter(|definition| definition.register == d && definition.address != op.address)
+                .map(|definition| definition.address)
+                .collect();
+            let redundant = !sources.is_empty()
+                && sources
+                    .iter()
+                    .all(|addr| *addr != ENTRY && lis_values.get(addr) == Some(value));
+            if redundant {
+                candidates.insert(op.address, sources);
+            }
+        }
+    }
+    // Two lis can only vouch for each other if neither is removed
+    candidates
+        .iter()
+        .filter(|(_, sources)| sources.iter().all(|addr| !candidates.contains_key(addr)))
+        .map(|(addr, _)| Edit::Remove(round.by_address[addr], "value already loaded"))
+        .collect()
+}
+
+fn dead_stores(round: &Round) -> Vec<Edit> {
+    let liveness = round.cfg.liveness();
+    let mut edits = Vec::new();
+    for (block_idx, block) in round.cfg.blocks.iter().enumerate() {
+        for (index, op) in block.ops.iter().enumerate() {
+            let live = liveness.live_after(&round.cfg, block_idx, index);
+            if !op.is_pure() || op.defs() & live != 0 {
+                continue;
+            }
+            if let Some(idx) = round.by_address.get(&op.address) {
+                if round.removable(*idx) {
+                    edits.push(Edit::Remove(*idx, "result never used"));
+                }
+            }
+        }
+    }
+    edits
+}
+
+// Gives every branch with a literal offset a label at its target, so it
+// keeps landing there as code moves. Returns the line of a branch whose
+// target isn't an instruction in .text, since then it can't be followed.
+fn label_literal_branches(lines: &mut [Line]) -> Result<(), usize> {
+    for idx in 0..lines.len() {
+        let line = &lines[idx];
+        let offset = match line.instruction {
+            Instruction::Beq {
+                i: Value::Literal(i),
+                ..
+            }
+            | Instruction::Bne {
+                i: Value::Literal(i),
+                ..
+            } if line.section == Section::Text => i as u16 as i16 as i32,
+            _ => continue,
+        };
+        let target = (line.address as i32 + 4 + 4 * offset) as u32;
+        let Some(target_idx) = lines.iter().position(|line| {
+            line.section == Section::Text
+                && line.address == target
+                && !matches!(
+                    line.instruction,
+                    Instruction::Noop | Instruction::Word { .. }
+                )
+        }) else {
+            return Err(idx);
+        };
+        // Contains '#', so it can't clash with a source label
+        let label = format!("0x{target:08x}#branch");
+        if !lines[target_idx].labels.contains(&format!("{label}:")) {
+            lines[target_idx].labels.push(format!("{label}:"));
+        }
+        match lines[idx].instruction {
+            Instruction::Beq { ref mut i, .. } | Instruction::Bne { ref mut i, .. } => {
+                *i = Value::Label(label)
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+fn text_words(lines: &mut [Line], bases: SectionBases, source_names: &[&str]) -> u32 {
+    let text = sections::layout(lines, bases, source_names)[0];
+    (text.end - text.start) / 4
+}
+
+// Rewrites the program until no pass finds anything more to do, returning a
+// report of each change. Code must only be reached through labels or branch
+// offsets, since it moves, and must not rely on branch delay slots.
+pub fn optimize(lines: &mut Vec<Line>, bases: SectionBases, source_names: &[&str]) -> String {
+    let words_before = text_words(lines, bases, source_names);
+    let mut labelled = lines.clone();
+    match label_literal_branches(&mut labelled) {
+        Ok(()) => *lines = labelled,
+        Err(idx) => {
+            let line = &lines[idx];
+            return format!(
+                "{}:{}: branch offset doesn't land on an instruction, so nothing was optimized\n",
+                source_names[line.file], line.line_number
+            );
+        }
+    }
+    let mut report = String::new();
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    loop {
+        sections::layout(lines, bases, source_names);
+        let labels = extract_label_locations(lines);
+        let resolved = replace_labels(lines, &labels);
+        let address_taken = cfg::address_taken(lines, &labels);
+        let by_address = lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| {
+                line.section == Section::Text && line.instruction != Instruction::Noop
+            })
+            .map(|(idx, line)| (line.address, idx))
+            .collect();
+        let round = Round {
+            lines,
+            labels,
+            by_address,
+            cfg: Cfg::new(&resolved, &SymbolTable::default(), &address_taken),
+        };
+        let Some(edits) = PASSES
+            .iter()
+            .map(|pass| pass(&round))
+            .find(|edits| !edits.is_empty())
+        else {
+            break;
+        };
+        let mut edits: Vec<(Edit, Option<usize>)> = edits
+            .into_iter()
+            .map(|edit| {
+                let operand = match edit {
+                    Edit::Remove(idx, _) => round.lis_operand(idx),
+                    Edit::Retarget(..) => None,
+                };
+                (edit, operand)
+            })
+            .collect();
+        edits.sort_by_key(|(edit, _)| match edit {
+            Edit::Remove(idx, _) | Edit::Retarget(idx, _) => *idx,
+        });
+
+        for (edit, operand) in edits {
+            let (idx, reason, change) = match edit {
+                Edit::Remove(idx, reason) => {
+                    let mut removed = lines[idx].instruction.to_string();
+                    if let Some(word) = operand {
+                        removed += &format!(" / {}", lines[word].instruction);
+                        lines[word].instruction = Instruction::Noop;
+                    }
+                    lines[idx].instruction = Instruction::Noop;
+                    (idx, reason, format!("removed {removed} ({reason})"))
+                }
+                Edit::Retarget(idx, target) => {
+                    let label = lines[idx].instruction.label_mut().unwrap();
+                    let change = format!(
+                        "branch to {} now goes straight to {}",
+                        source_name(label),
+                        source_name(&target)
+                    );
+                    *label = target;
+                    (idx, "branch chain threaded", change)
+                }
+            };
+            let line = &lines[idx];
+            report += &format!(
+                "{}:{}: {change}\n",
+                source_names[line.file], line.line_number
+            );
+            *counts.entry(reason).or_default() += 1;
+        }
+    }
+
+    let words_after = text_words(lines, bases, source_names);
+    report += &format!("Optimizer: .text went from {words_before} to {words_after} words\n");
+    for (reason, count) in counts {
+        report += &format!("  {reason}: {count}\n");
+    }
+    report
+}
+
+// Runs the original and optimized programs to completion and checks that
+// they print the same output and finish with the same result. Registers
+// holding addresses can legitimately differ, so only $3 is compared.
+pub fn verify(
+    original: (MipsEmulator, SharedBuffer),
+    optimized: (MipsEmulator, SharedBuffer),
+) -> bool {
+    let (mut original, original_output) = original;
+    let (mut optimized, optimized_output) = optimized;
+    original.run();
+    optimized.run();
+    original.devices.flush();
+    optimized.devices.flush();
+
+    let mut differences = Vec::new();
+    let (a, b) = (original.registers[3], optimized.registers[3]);
+    if a != b {
+        differences.push(format!("$3: 0x{a:08x} != 0x{b:08x}"));
+    }
+    if original.exit_status != optimized.exit_status {
+        differences.push(format!(
+            "exit status: {:?} != {:?}",
+            original.exit_status, optimized.exit_status
+        ));
+    }
+    if original_output.contents() != optimized_output.contents() {
+        differences.push(String::from("program output differs"));
+    }
+    for difference in &differences {
+        eprintln!("verify-optimization: {difference}");
+    }
+    if differences.is_empty() {
+        eprintln!(
+            "verify-optimization: same result in {} instructions instead of {}",
+            optimized.steps, original.steps
+        );
+    }
+    differences.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufRead};
+
+    use super::*;
+    use crate::conditionals::Defines;
+    use crate::{assemble, parse_lines, relax};
+
+    fn parse(source: &str) -> Vec<Line> {
+        parse_lines(io::Cursor::new(source).lines(), 0, &Defines::new())
+    }
+
+    // $3 after running the program with $1 and $2 set to the inputs
+    fn result(lines: &[Line], inputs: [u32; 2]) -> u32 {
+        let mut lines = lines.to_vec();
+        let sections = relax::layout(&mut lines, SectionBases::default(), &["test.asm"], None);
+        let resolved = replace_labels(&lines, &extract_label_locations(&lines));
+        let mut emulator = MipsEmulator::new(&assemble(&resolved, &sections));
+        emulator.registers[1] = inputs[0];
+        emulator.registers[2] = inputs[1];
+        emulator.run();
+        emulator.registers[3]
+    }
+
+    // Optimizes the program, checking that it still computes the same result
+    // for each pair of inputs, and returns the report
+    fn optimize_and_compare(source: &str, inputs: &[[u32; 2]]) -> String {
+        let original = parse(source);
+        let mut lines = original.clone();
+        let report = optimize(&mut lines, SectionBases::default(), &["test.asm"]);
+        for input in inputs {
+            assert_eq!(
+                result(&original, *input),
+                result(&lines, *input),
+                "{report}"
+            );
+        }
+        report
+    }
+
+    #[test]
+    fn removes_moves_to_self() {
+        let report = optimize_and_compare(
+            "add $3, $1, $0\n\
+             add $3, $3, $0\n\
+             jr $31\n",
+            &[[7, 0]],
+        );
+        assert!(report.contains("test.asm:2: removed add $3, $3, $0 (move to itself)"));
+        assert!(report.contains(".text went from 3 to 2 words"));
+    }
+
+    #[test]
+    fn removes_branches_to_next() {
+        let report = optimize_and_compare(
+            "beq $1, $2, next\n\
+             next:\n\
+             add $3, $1, $2\n\
+             jr $31\n",
+            &[[1, 1], [1, 2]],
+        );
+        assert!(report.contains("branch to the next instruction"));
+    }
+
+    #[test]
+    fn threads_branch_chains() {
+        let report = optimize_and_compare(
+            "beq $1, $2, hop\n\
+             add $3, $1, $0\n\
+             jr $31\n\
+             hop:\n\
+             beq $0, $0, done\n\
+             add $3, $2, $0\n\
+             done:\n\
+             add $3, $1, $2\n\
+             jr $31\n",
+            &[[1, 1], [1, 2]],
+        );
+        assert!(report.contains("branch to hop now goes straight to done"));
+    }
+
+    #[test]
+    fn removes_redundant_lis() {
+        let report = optimize_and_compare(
+            "lis $4\n\
+             .word 10\n\
+             add $3, $4, $1\n\
+             lis $4\n\
+             .word 10\n\
+             add $3, $3, $4\n\
+             jr $31\n",
+            &[[5, 0]],
+        );
+        assert!(report.contains("test.asm:4: removed lis $4 / .word 10 (value already loaded)"));
+    }
+
+    #[test]
+    fn removes_dead_stores() {
+        let report = optimize_and_compare(
+            "add $3, $1, $2\n\
+             add $3, $1, $0\n\
+             jr $31\n",
+            &[[3, 4]],
+        );
+        assert!(report.contains("test.asm:1: removed add $3, $1, $2 (result never used)"));
+    }
+
+    #[test]
+    fn follows_literal_branch_offsets() {
+        // The loop branches back over the move, which is removed
+        let report = optimize_and_compare(
+            "lis $4\n\
+             .word 3\n\
+             add $3, $0, $0\n\
+             lis $5\n\
+             .word 1\n\
+             add $3, $3, $4\n\
+             add $6, $6, $0\n\
+             sub $4, $4, $5\n\
+             bne $4, $0, -4\n\
+             jr $31\n",
+            &[[0, 0]],
+        );
+        assert!(report.contains("removed add $6, $6, $0 (move to itself)"));
+    }
+
+    #[test]
+    fn leaves_branches_into_data_alone() {
+        let source = "lis $4\n\
+                      .word 3\n\
+                      add $3, $3, $0\n\
+                      bne $3, $0, -3\n\
+                      jr $31\n";
+        let report = optimize_and_compare(source, &[[0, 0]]);
+        assert!(report.contains("test.asm:4: branch offset doesn't land on an instruction"));
+    }
+
+    #[test]
+    fn address_taken_labels_are_entries() {
+        // foo is called with $2 = 7 and only falls through with $2 = 5, so
+        // its lis can't be removed
+        let report = optimize_and_compare(
+            "lis $7\n\
+             .word foo\n\
+             go:\n\
+             add $6, $31, $0\n\
+             lis $2\n\
+             .word 7\n\
+             jalr $7\n\
+             add $31, $6, $0\n\
+             add $4, $3, $0\n\
+             bne $0, $0, go\n\
+             lis $2\n\
+             .word 5\n\
+             foo:\n\
+             lis $2\n\
+             .word 5\n\
+             add $3, $2, $4\n\
+             jr $31\n",
+            &[[0, 0]],
+        );
+        assert!(!report.contains("value already loaded"));
+    }
+
+    #[test]
+    fn labels_stored_in_data_are_entries() {
+        let report = optimize_and_compare(
+            "lw $7, pointer($0)\n\
+             add $6, $31, $0\n\
+             lis $2\n\
+             .word 7\n\
+             jalr $7\n\
+             add $31, $6, $0\n\
+             add $4, $3, $0\n\
+             lis $2\n\
+             .word 5\n\
+             foo:\n\
+             lis $2\n\
+             .word 5\n\
+             add $3, $2, $4\n\
+             jr $31\n\
+             .data\n\
+             pointer: .word foo\n",
+            &[[0, 0]],
+        );
+        assert!(!report.contains("value already loaded"));
+    }
+}
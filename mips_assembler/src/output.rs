@@ -0,0 +1,176 @@
+use std::fmt::Write;
+
+use crate::sections::Segment;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    BinBe,
+    BinLe,
+    IntelHex,
+    Srec,
+    Memh,
+    CArray,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "bin-be" | "bin" => Some(OutputFormat::BinBe),
+            "bin-le" => Some(OutputFormat::BinLe),
+            "hex" | "ihex" => Some(OutputFormat::IntelHex),
+            "srec" => Some(OutputFormat::Srec),
+            "memh" => Some(OutputFormat::Memh),
+            "c-array" => Some(OutputFormat::CArray),
+            _ => None,
+        }
+    }
+
+    // Segments must be in address order. The formats that record addresses
+    // write each segment at its own; the others hold a single image starting
+    // at the first segment, with the gaps between segments filled with zeros.
+    pub fn encode(&self, segments: &[Segment], load_address: u32) -> Vec<u8> {
+        match *self {
+            OutputFormat::BinBe => image(segments)
+                .iter()
+                .flat_map(|w| w.to_be_bytes())
+                .collect(),
+            OutputFormat::BinLe => image(segments)
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect(),
+            OutputFormat::IntelHex => to_intel_hex(segments, load_address).into_bytes(),
+            OutputFormat::Srec => to_srec(segments, load_address).into_bytes(),
+            OutputFormat::Memh => to_memh(segments, load_address).into_bytes(),
+            OutputFormat::CArray => to_c_array(&image(segments), load_address).into_bytes(),
+        }
+    }
+}
+
+fn image(segments: &[Segment]) -> Vec<u32> {
+    let Some(first) = segments.first() else {
+        return Vec::new();
+    };
+    let mut words = Vec::new();
+    for segment in segments {
+        words.resize((segment.start - first.start) as usize / 4, 0);
+        words.extend_from_slice(&segment.words);
+    }
+    words
+}
+
+fn be_bytes(machine_code: &[u32]) -> Vec<u8> {
+    machine_code.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+// Intel HEX: 16-byte data records, with an extended linear address record
+// whenever the upper half of the address changes, and a start address record
+// pointing at the load address.
+fn to_intel_hex(segments: &[Segment], load_address: u32) -> String {
+    fn record(result: &mut String, kind: u8, addr: u16, data: &[u8]) {
+        let mut checksum = (data.len() as u8)
+            .wrapping_add((addr >> 8) as u8)
+            .wrapping_add(addr as u8)
+            .wrapping_add(kind);
+        write!(result, ":{:02X}{addr:04X}{kind:02X}", data.len()).unwrap();
+        for byte in data {
+            checksum = checksum.wrapping_add(*byte);
+            write!(result, "{byte:02X}").unwrap();
+        }
+        writeln!(result, "{:02X}", checksum.wrapping_neg()).unwrap();
+    }
+
+    let mut result = String::new();
+    let mut upper: Option<u16> = None;
+    for segment in segments {
+        let bytes = be_bytes(&segment.words);
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = segment.start.wrapping_add(offset as u32);
+            if upper != Some((addr >> 16) as u16) {
+                upper = Some((addr >> 16) as u16);
+                record(&mut result, 0x04, 0, &((addr >> 16) as u16).to_be_bytes());
+            }
+            // Records may not cross a 64K boundary
+            let to_boundary = 0x10000 - (addr & 0xFFFF) as usize;
+            let len = 16.min(bytes.len() - offset).min(to_boundary);
+            record(&mut result, 0x00, addr as u16, &bytes[offset..offset + len]);
+            offset += len;
+        }
+    }
+    record(&mut result, 0x05, 0, &load_address.to_be_bytes());
+    record(&mut result, 0x01, 0, &[]);
+    result
+}
+
+// Motorola S-records with 32-bit addresses: an S0 header, S3 data records,
+// a record count (S5, or S6 past 0xFFFF records) and an S7 termination
+// record holding the entry point.
+fn to_srec(segments: &[Segment], load_address: u32) -> String {
+    fn record(result: &mut String, kind: u8, addr: u32, addr_len: usize, data: &[u8]) {
+        let addr_bytes = &addr.to_be_bytes()[4 - addr_len..];
+        let count = (addr_len + data.len() + 1) as u8;
+        let mut sum = count;
+        write!(result, "S{kind}{count:02X}").unwrap();
+        for byte in addr_bytes.iter().chain(data) {
+            sum = sum.wrapping_add(*byte);
+            write!(result, "{byte:02X}").unwrap();
+        }
+        writeln!(result, "{:02X}", !sum).unwrap();
+    }
+
+    let mut result = String::new();
+    record(&mut result, 0, 0, 2, b"mips_assembler");
+    let mut count = 0;
+    for segment in segments {
+        let bytes = be_bytes(&segment.words);
+        for (idx, chunk) in bytes.chunks(16).enumerate() {
+            let addr = segment.start.wrapping_add(16 * idx as u32);
+            record(&mut result, 3, addr, 4, chunk);
+            count += 1;
+        }
+    }
+    if count <= 0xFFFF {
+        record(&mut result, 5, count, 2, &[]);
+    } else {
+        record(&mut result, 6, count, 3, &[]);
+    }
+    record(&mut result, 7, load_address, 4, &[]);
+    result
+}
+
+// Verilog $readmemh: addresses are in units of the 32-bit memory word.
+fn to_memh(segments: &[Segment], load_address: u32) -> String {
+    let mut result = String::new();
+    writeln!(result, "// load address 0x{load_address:08x}").unwrap();
+    for segment in segments {
+        writeln!(result, "@{:08x}", segment.start / 4).unwrap();
+        for word in &segment.words {
+            writeln!(result, "{word:08x}").unwrap();
+        }
+    }
+    result
+}
+
+fn to_c_array(machine_code: &[u32], load_address: u32) -> String {
+    let mut result = String::new();
+    writeln!(result, "#include <stdint.h>").unwrap();
+    writeln!(result).unwrap();
+    writeln!(
+        result,
+        "const uint32_t mips_program_load_address = 0x{load_address:08x};"
+    )
+    .unwrap();
+    writeln!(
+        result,
+        "const uint32_t mips_program_length = {};",
+        machine_code.len()
+    )
+    .unwrap();
+    writeln!(result, "const uint32_t mips_program[] = {{").unwrap();
+    for chunk in machine_code.chunks(4) {
+        let words: Vec<String> = chunk.iter().map(|w| format!("0x{w:08x},")).collect();
+        writeln!(result, "    {}", words.join(" ")).unwrap();
+    }
+    writeln!(result, "}};").unwrap();
+    result
+}
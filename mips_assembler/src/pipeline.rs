@@ -0,0 +1,125 @@
+use std::fmt::Write;
+
+use crate::Instruction;
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    // Cycles lost on a taken branch or jump; branches are predicted not taken
+    pub branch_penalty: u64,
+    pub mult_latency: u64,
+    pub div_latency: u64,
+    pub delay_slots: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        PipelineConfig {
+            branch_penalty: 2,
+            mult_latency: 5,
+            div_latency: 35,
+            delay_slots: false,
+        }
+    }
+}
+
+// Classic IF/ID/EX/MEM/WB pipeline with full forwarding. Only stalls are
+// modelled; everything else retires one instruction per cycle.
+#[derive(Debug, Default)]
+pub struct PipelineModel {
+    config: PipelineConfig,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub load_use_stalls: u64,
+    pub branch_stalls: u64,
+    pub hilo_stalls: u64,
+    pub lis_cycles: u64,
+    last_load: Option<u8>,
+    hilo_ready: u64,
+}
+
+impl PipelineModel {
+    pub fn new(config: PipelineConfig) -> PipelineModel {
+        PipelineModel {
+            config,
+            // Filling the pipeline before the first instruction retires
+            cycles: 4,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, instruction: &Instruction, branched: bool) {
+        self.instructions += 1;
+
+        // A loaded value is only available after MEM, one cycle too late for
+        // an instruction that needs it in EX
+        if let Some(reg) = self.last_load {
+            if instruction.sources().contains(&reg) {
+                self.load_use_stalls += 1;
+                self.cycles += 1;
+            }
+        }
+        self.last_load = match *instruction {
+            Instruction::Lw { .. } => instruction.destination().filter(|reg| *reg != 0),
+            _ => None,
+        };
+
+        match *instruction {
+            Instruction::Mfhi { .. }
+            | Instruction::Mflo { .. }
+            | Instruction::Mult { .. }
+            | Instruction::Multu { .. }
+            | Instruction::Div { .. }
+            | Instruction::Divu { .. } => {
+                // Reading hi/lo or starting another operation waits for the
+                // multiply/divide unit to finish
+                let stall = self.hilo_ready.saturating_sub(self.cycles + 1);
+                self.hilo_stalls += stall;
+                self.cycles += stall;
+            }
+            _ => {}
+        }
+        self.cycles += 1;
+        match *instruction {
+            Instruction::Mult { .. } | Instruction::Multu { .. } => {
+                self.hilo_ready = self.cycles + self.config.mult_latency
+            }
+            Instruction::Div { .. } | Instruction::Divu { .. } => {
+                self.hilo_ready = self.cycles + self.config.div_latency
+            }
+            // The immediate word occupies a fetch slot of its own
+            Instruction::Lis { .. } => {
+                self.lis_cycles += 1;
+                self.cycles += 1;
+            }
+            _ => {}
+        }
+
+        if branched {
+            let penalty = if self.config.delay_slots {
+                self.config.branch_penalty.saturating_sub(1)
+            } else {
+                self.config.branch_penalty
+            };
+            self.branch_stalls += penalty;
+            self.cycles += penalty;
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut result = String::new();
+        writeln!(
+            result,
+            "Pipeline: {} cycles for {} instructions (CPI {:.3})",
+            self.cycles,
+            self.instructions,
+            self.cycles as f64 / self.instructions.max(1) as f64
+        )
+        .unwrap();
+        writeln!(result, "  pipeline fill     {:>12}", 4).unwrap();
+        writeln!(result, "  load-use stalls   {:>12}", self.load_use_stalls).unwrap();
+        writeln!(result, "  branch penalties  {:>12}", self.branch_stalls).unwrap();
+        writeln!(result, "  hi/lo stalls      {:>12}", self.hilo_stalls).unwrap();
+        writeln!(result, "  lis immediates    {:>12}", self.lis_cycles).unwrap();
+        result
+    }
+}
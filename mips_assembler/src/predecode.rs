@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::jit::{self, CompiledBlock};
+use crate::{Instruction, MipsEmulator, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Engine {
+    // step() for every instruction
+    Reference,
+    // Decoded basic blocks
+    Predecode,
+    // Decoded basic blocks, with hot ones compiled to closures
+    Jit,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "reference" => Some(Engine::Reference),
+            "predecode" => Some(Engine::Predecode),
+            "jit" => Some(Engine::Jit),
+            _ => None,
+        }
+    }
+}
+
+// Longest run of straight-line instructions decoded into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+// An instruction with its operands pulled out of the encoding ahead of time.
+// Offsets are sign-extended and branch offsets are already in bytes.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Add { d: u8, s: u8, t: u8 },
+    Sub { d: u8, s: u8, t: u8 },
+    Slt { d: u8, s: u8, t: u8 },
+    Sltu { d: u8, s: u8, t: u8 },
+    Mult { s: u8, t: u8 },
+    Multu { s: u8, t: u8 },
+    Div { s: u8, t: u8 },
+    Divu { s: u8, t: u8 },
+    Mfhi { d: u8 },
+    Mflo { d: u8 },
+    Lis { d: u8, value: u32 },
+    Lw { t: u8, s: u8, offset: i32 },
+    Sw { t: u8, s: u8, offset: i32 },
+    Beq { s: u8, t: u8, offset: i32 },
+    Bne { s: u8, t: u8, offset: i32 },
+    Jr { s: u8 },
+    Jalr { s: u8 },
+}
+
+impl Op {
+    // None for instructions the predecoder leaves to the reference interpreter
+    fn decode(instruction: &Instruction, next_word: Option<u32>) -> Option<Op> {
+        let immediate = |i: &Value| match i {
+            Value::Literal(i) => (*i as i16) as i32,
+            Value::Label(_) => unreachable!(),
+        };
+        Some(match *instruction {
+            Instruction::Add { d, s, t } => Op::Add { d, s, t },
+            Instruction::Sub { d, s, t } => Op::Sub { d, s, t },
+            Instruction::Slt { d, s, t } => Op::Slt { d, s, t },
+            Instruction::Sltu { d, s, t } => Op::Sltu { d, s, t },
+            Instruction::Mult { s, t } => Op::Mult { s, t },
+            Instruction::Multu { s, t } => Op::Multu { s, t },
+            Instruction::Div { s, t } => Op::Div { s, t },
+            Instruction::Divu { s, t } => Op::Divu { s, t },
+            Instruction::Mfhi { d } => Op::Mfhi { d },
+            Instruction::Mflo { d } => Op::Mflo { d },
+            Instruction::Lis { d } => Op::Lis {
+                d,
+                value: next_word?,
+            },
+            Instruction::Lw { t, ref i, s } => Op::Lw {
+                t,
+                s,
+                offset: immediate(i),
+            },
+            Instruction::Sw { t, ref i, s } => Op::Sw {
+                t,
+                s,
+                offset: immediate(i),
+            },
+            Instruction::Beq { s, t, ref i } => Op::Beq {
+                s,
+                t,
+                offset: 4 * immediate(i),
+            },
+            Instruction::Bne { s, t, ref i } => Op::Bne {
+                s,
+                t,
+                offset: 4 * immediate(i),
+            },
+            Instruction::Jr { s } => Op::Jr { s },
+            Instruction::Jalr { s } => Op::Jalr { s },
+            Instruction::Syscall | Instruction::Word { .. } | Instruction::Noop => return None,
+        })
+    }
+
+    fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Op::Beq { .. } | Op::Bne { .. } | Op::Jr { .. } | Op::Jalr { .. }
+        )
+    }
+}
+
+// Decoded straight-line runs of code keyed by their start address. Any write
+// to a word that was decoded throws the whole cache away, so self-modifying
+// code still sees its own stores.
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<[Op]>>,
+    code_words: HashSet<u32>,
+    compiled: HashMap<u32, Rc<CompiledBlock>>,
+    executions: HashMap<u32, u32>,
+    // Blocks that took a side exit and stay with the predecoder
+    uncompilable: HashSet<u32>,
+    pub invalidations: u64,
+}
+
+impl BlockCache {
+    pub fn is_code(&self, word: u32) -> bool {
+        self.code_words.contains(&word)
+    }
+
+    pub fn invalidate(&mut self, word: u32) {
+        if self.code_words.contains(&word) {
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code_words.clear();
+        self.compiled.clear();
+        self.executions.clear();
+        self.uncompilable.clear();
+        self.invalidations += 1;
+    }
+}
+
+impl MipsEmulator {
+    // The instrumentation hooks in step() need to see every instruction, and
+    // periodic snapshots need exact instruction counts
+    pub(crate) fn can_predecode(&self) -> bool {
+        self.engine != Engine::Reference
+            && !self.trace
+            && !self.delay_slots
+            && self.stats.is_none()
+            && self.profiler.is_none()
+            && self.coverage.is_none()
+            && self.pipeline.is_none()
+            && self.icache.is_none()
+            && self.dcache.is_none()
+            && self.heap_checker.is_none()
+            && self.sanitizer.is_none()
+            && self.call_checker.is_none()
+            && self.snapshot_interval.is_none()
+    }
+
+    fn decode_block(&mut self, start: u32) -> Option<Rc<[Op]>> {
+        if let Some(block) = self.blocks.blocks.get(&start) {
+            return Some(block.clone());
+        }
+        let mut ops = Vec::new();
+        let mut words = Vec::new();
+        let mut addr = start;
+        while ops.len() < MAX_BLOCK_LEN && !self.devices.contains(addr) {
+            let Some(&word) = self.memory.get(&(addr / 4)) else {
+                break;
+            };
+            let next_word = self.memory.get(&(addr / 4 + 1)).copied();
+            let Some(op) = Op::decode(&Instruction::disassemble(word), next_word) else {
+                break;
+            };
+            words.push(addr / 4);
+            addr += 4;
+            if let Op::Lis { .. } = op {
+                words.push(addr / 4);
+                addr += 4;
+            }
+            ops.push(op);
+            if op.ends_block() {
+                break;
+            }
+        }
+        if ops.is_empty() {
+            return None;
+        }
+        let block: Rc<[Op]> = ops.into();
+        self.blocks.blocks.insert(start, block.clone());
+        self.blocks.code_words.extend(words);
+        Some(block)
+    }
+
+    fn compiled_block(&mut self, start: u32) -> Option<Rc<CompiledBlock>> {
+        if let Some(block) = self.blocks.compiled.get(&start) {
+            return Some(block.clone());
+        }
+        if self.blocks.uncompilable.contains(&start) {
+            return None;
+        }
+        let executions = self.blocks.executions.entry(start).or_insert(0);
+        *executions += 1;
+        if *executions < jit::HOT_THRESHOLD {
+            return None;
+        }
+        let block = Rc::new(jit::compile(start, &self.decode_block(start)?));
+        self.blocks.compiled.insert(start, block.clone());
+        Some(block)
+    }
+
+    // Runs one basic block, or a single instruction through step() when the
+    // code at pc can't be decoded (syscalls, data words, uninitialized memory)
+    pub(crate) fn dispatch(&mut self) {
+        let start = self.pc;
+        if self.engine == Engine::Jit {
+            if let Some(block) = self.compiled_block(start) {
+                if !self.run_compiled(&block) {
+                    self.blocks.compiled.remove(&start);
+                    self.blocks.uncompilable.insert(start);
+                }
+                return;
+            }
+        }
+        let Some(block) = self.decode_block(start) else {
+            self.step();
+            return;
+        };
+        let invalidations = self.blocks.invalidations;
+        for op in block.iter() {
+            self.devices.tick();
+            self.steps += 1;
+            self.pc += 4;
+            self.execute(*op);
+            if self.blocks.invalidations != invalidations || self.exit_status.is_some() {
+                break;
+            }
+        }
+    }
+
+    fn execute(&mut self, op: Op) {
+        let r = |reg: u8| reg as usize;
+        match op {
+            Op::Add { d, s, t } => {
+                self.registers[r(d)] = self.registers[r(s)].wrapping_add(self.registers[r(t)])
+            }
+            Op::Sub { d, s, t } => {
+                self.registers[r(d)] = self.registers[r(s)].wrapping_sub(self.registers[r(t)])
+            }
+            Op::Slt { d, s, t } => {
+                self.registers[r(d)] =
+                    ((self.registers[r(s)] as i32) < (self.registers[r(t)] as i32)) as u32
+            }
+            Op::Sltu { d, s, t } => {
+                self.registers[r(d)] = (self.registers[r(s)] <= self.registers[r(t)]) as u32
+            }
+            Op::Mult { s, t } => {
+                let product =
+                    ((self.registers[r(s)] as i64) * (self.registers[r(t)] as i64)) as u64;
+                self.hi = (product >> 32) as u32;
+                self.lo = product as u32;
+            }
+            Op::Multu { s, t } => {
+                let product = (self.registers[r(s)] as u64) * (self.registers[r(t)] as u64);
+                self.hi = (product >> 32) as u32;
+                self.lo = product as u32;
+            }
+            Op::Div { s, t } => {
+                let s = self.registers[r(s)] as i32;
+                let t = self.registers[r(t)] as i32;
+                self.lo = (s / t) as u32;
+                self.hi = (s % t) as u32;
+            }
+            Op::Divu { s, t } => {
+                let s = self.registers[r(s)];
+                let t = self.registers[r(t)];
+                self.lo = s / t;
+                self.hi = s % t;
+            }
+            Op::Mfhi { d } => self.registers[r(d)] = self.hi,
+            Op::Mflo { d } => self.registers[r(d)] = self.lo,
+            Op::Lis { d, value } => {
+                self.registers[r(d)] = value;
+                self.pc += 4;
+            }
+            Op::Lw { t, s, offset } => {
+                let addr = (self.registers[r(s)] as i32 + offset) as u32;
+                self.registers[r(t)] = self.read(addr);
+            }
+            Op::Sw { t, s, offset } => {
+                let addr = (self.registers[r(s)] as i32 + offset) as u32;
+                self.write(addr, self.registers[r(t)]);
+            }
+            Op::Beq { s, t, offset } => {
+                if s == t || self.registers[r(s)] == self.registers[r(t)] {
+                    self.pc = (self.pc as i32 + offset) as u32;
+                }
+            }
+            Op::Bne { s, t, offset } => {
+                if s != t && self.registers[r(s)] != self.registers[r(t)] {
+                    self.pc = (self.pc as i32 + offset) as u32;
+                }
+            }
+            Op::Jr { s } => self.pc = self.registers[r(s)],
+            Op::Jalr { s } => {
+                let target = self.registers[r(s)];
+                self.registers[31] = self.pc;
+                self.pc = target;
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::symbols::SymbolTable;
+
+// One node per distinct call stack, so that per-instruction bookkeeping is a
+// single counter increment and folded stacks fall out of walking the parents.
+struct StackNode {
+    parent: Option<usize>,
+    function: usize,
+}
+
+struct Frame {
+    node: usize,
+    return_address: u32,
+}
+
+pub struct Profiler {
+    symbols: SymbolTable,
+    functions: Vec<String>,
+    function_ids: HashMap<String, usize>,
+    nodes: Vec<StackNode>,
+    children: HashMap<(Option<usize>, usize), usize>,
+    node_counts: Vec<u64>,
+    address_counts: HashMap<u32, u64>,
+    calls: HashMap<(usize, usize), u64>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new(symbols: SymbolTable, entry: u32, return_address: u32) -> Profiler {
+        let mut result = Profiler {
+            symbols,
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            node_counts: Vec::new(),
+            address_counts: HashMap::new(),
+            calls: HashMap::new(),
+            stack: Vec::new(),
+        };
+        let function = result.function_at(entry);
+        let node = result.node(None, function);
+        result.stack.push(Frame {
+            node,
+            return_address,
+        });
+        result
+    }
+
+    fn function_at(&mut self, addr: u32) -> usize {
+        let name = match self.symbols.name_at(addr) {
+            Some(name) => name.to_string(),
+            None => self.symbols.describe(addr),
+        };
+        if let Some(id) = self.function_ids.get(&name) {
+            return *id;
+        }
+        self.functions.push(name.clone());
+        self.function_ids.insert(name, self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    fn node(&mut self, parent: Option<usize>, function: usize) -> usize {
+        if let Some(node) = self.children.get(&(parent, function)) {
+            return *node;
+        }
+        self.nodes.push(StackNode { parent, function });
+        self.node_counts.push(0);
+        self.children
+            .insert((parent, function), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    pub fn record_instruction(&mut self, pc: u32) {
+        *self.address_counts.entry(pc).or_insert(0) += 1;
+        if let Some(frame) = self.stack.last() {
+            self.node_counts[frame.node] += 1;
+        }
+    }
+
+    pub fn record_call(&mut self, target: u32, return_address: u32) {
+        let callee = self.function_at(target);
+        let parent = self.stack.last().map(|frame| frame.node);
+        if let Some(parent) = parent {
+            let caller = self.nodes[parent].function;
+            *self.calls.entry((caller, callee)).or_insert(0) += 1;
+        }
+        let node = self.node(parent, callee);
+        self.stack.push(Frame {
+            node,
+            return_address,
+        });
+    }
+
+    // A `jr` to an address on the shadow stack is a return (possibly
+    // unwinding several frames); anything else is treated as a tail call.
+    pub fn record_jump(&mut self, target: u32) {
+        if let Some(depth) = self
+            .stack
+            .iter()
+            .rposition(|frame| frame.return_address == target)
+        {
+            self.stack.truncate(depth);
+            return;
+        }
+        let callee = self.function_at(target);
+        if let Some(frame) = self.stack.pop() {
+            let parent = self.nodes[frame.node].parent;
+            let caller = self.nodes[frame.node].function;
+            *self.calls.entry((caller, callee)).or_insert(0) += 1;
+            let node = self.node(parent, callee);
+            self.stack.push(Frame {
+                node,
+                return_address: frame.return_address,
+            });
+        }
+    }
+
+    fn path(&self, node: usize) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut current = Some(node);
+        while let Some(node) = current {
+            result.push(self.nodes[node].function);
+            current = self.nodes[node].parent;
+        }
+        result.reverse();
+        result
+    }
+
+    pub fn folded_stacks(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for (node, count) in self.node_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let names: Vec<&str> = self
+                .path(node)
+                .iter()
+                .map(|function| self.functions[*function].as_str())
+                .collect();
+            lines.push(format!("{} {count}", names.join(";")));
+        }
+        lines.sort();
+        let mut result = lines.join("\n");
+        result.push('\n');
+        result
+    }
+
+    pub fn report(&self) -> String {
+        let total: u64 = self.node_counts.iter().sum();
+        let mut exclusive = vec![0_u64; self.functions.len()];
+        let mut inclusive = vec![0_u64; self.functions.len()];
+        let mut edge_inclusive: HashMap<(usize, usize), u64> = HashMap::new();
+        for (node, count) in self.node_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let path = self.path(node);
+            exclusive[*path.last().unwrap()] += count;
+            // Recursive functions only count once per stack
+            let distinct: HashSet<&usize> = path.iter().collect();
+            for function in distinct {
+                inclusive[*function] += count;
+            }
+            let distinct: HashSet<(usize, usize)> =
+                path.windows(2).map(|pair| (pair[0], pair[1])).collect();
+            for edge in distinct {
+                *edge_inclusive.entry(edge).or_insert(0) += count;
+            }
+        }
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+
+        let mut result = String::new();
+        writeln!(result, "Flat profile by function ({total} instructions):").unwrap();
+        writeln!(
+            result,
+            "  {:>12} {:>7} {:>12} {:>7}  function",
+            "self", "%", "total", "%"
+        )
+        .unwrap();
+        let mut order: Vec<usize> = (0..self.functions.len()).collect();
+        order.sort_by_key(|function| std::cmp::Reverse(exclusive[*function]));
+        for function in order {
+            writeln!(
+                result,
+                "  {:>12} {:>6.2}% {:>12} {:>6.2}%  {}",
+                exclusive[function],
+                percent(exclusive[function]),
+                inclusive[function],
+                percent(inclusive[function]),
+                self.functions[function]
+            )
+            .unwrap();
+        }
+
+        writeln!(result, "Flat profile by label:").unwrap();
+        let mut by_label: HashMap<String, u64> = HashMap::new();
+        for (addr, count) in &self.address_counts {
+            let label = match self.symbols.enclosing(*addr) {
+                Some((name, _)) => name.to_string(),
+                None => format!("0x{addr:08x}"),
+            };
+            *by_label.entry(label).or_insert(0) += count;
+        }
+        let mut by_label: Vec<(String, u64)> = by_label.into_iter().collect();
+        by_label.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (label, count) in by_label {
+            writeln!(result, "  {count:>12} {:>6.2}%  {label}", percent(count)).unwrap();
+        }
+
+        writeln!(result, "Call graph:").unwrap();
+        writeln!(result, "  {:>8} {:>12}  caller -> callee", "calls", "total").unwrap();
+        let mut edges: Vec<(&(usize, usize), &u64)> = self.calls.iter().collect();
+        edges.sort_by_key(|(edge, _)| {
+            (
+                self.functions[edge.0].as_str(),
+                self.functions[edge.1].as_str(),
+            )
+        });
+        for (edge, calls) in edges {
+            writeln!(
+                result,
+                "  {calls:>8} {:>12}  {} -> {}",
+                edge_inclusive.get(edge).unwrap_or(&0),
+                self.functions[edge.0],
+                self.functions[edge.1]
+            )
+            .unwrap();
+        }
+        result
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+// Conventional MIPS register names, the same in every dialect. Native
+// programs keep the stack pointer in $30, which these names call $fp, so they
+// should write it as $30; MARS programs use $sp ($29) as usual.
+const NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+    "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp", "fp",
+    "ra",
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RegisterNames {
+    #[default]
+    Numeric,
+    Symbolic,
+}
+
+impl RegisterNames {
+    pub fn from_name(name: &str) -> Option<RegisterNames> {
+        match name {
+            "numeric" => Some(RegisterNames::Numeric),
+            "symbolic" => Some(RegisterNames::Symbolic),
+            _ => None,
+        }
+    }
+
+    pub fn name(self, reg: u8) -> String {
+        match self {
+            RegisterNames::Numeric => format!("${reg}"),
+            RegisterNames::Symbolic => format!("${}", NAMES[reg as usize]),
+        }
+    }
+}
+
+// Per-file register aliases declared with `.reg name, $N`
+#[derive(Debug, Default)]
+pub struct Aliases {
+    aliases: HashMap<String, u8>,
+}
+
+impl Aliases {
+    // Parses the operands of a .reg directive
+    pub fn declare(&mut self, operands: &[&str]) {
+        let [name, register] = operands else {
+            panic!(
+                "Expected .reg name, $N but got .reg {}",
+                operands.join(", ")
+            );
+        };
+        let name = name.trim_start_matches('$');
+        if name.parse::<u8>().is_ok() || NAMES.contains(&name) {
+            panic!("Register alias {name} shadows a register name");
+        }
+        let register = self.parse(register);
+        self.aliases.insert(name.to_string(), register);
+    }
+
+    // Parses $N, a conventional name like $sp, or an alias declared with .reg
+    pub fn parse(&self, token: &str) -> u8 {
+        if !token.starts_with('$') {
+            panic!("Expected a register but got {token}");
+        }
+        self.lookup(token)
+            .unwrap_or_else(|| panic!("Invalid register {token}"))
+    }
+
+    pub fn lookup(&self, token: &str) -> Option<u8> {
+        let name = token.strip_prefix('$')?;
+        if let Ok(reg) = name.parse::<u8>() {
+            (reg < 32).then_some(reg)
+        } else if let Some(reg) = NAMES.iter().position(|other| *other == name) {
+            Some(reg as u8)
+        } else {
+            self.aliases.get(name).copied()
+        }
+    }
+}
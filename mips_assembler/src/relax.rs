@@ -0,0 +1,135 @@
+use crate::sections::{self, SectionBases, SectionRange};
+use crate::{extract_label_locations, Instruction, Line, Value};
+
+// Branch offsets are signed 16-bit word counts
+fn in_range(offset: i64) -> bool {
+    (i16::MIN as i64..=i16::MAX as i64).contains(&offset)
+}
+
+// Indices of label branches whose target is too far away to encode
+fn far_branches(lines: &Vec<Line>) -> Vec<usize> {
+    let labels = extract_label_locations(lines);
+    let mut result = Vec::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let (Instruction::Beq {
+            i: Value::Label(label),
+            ..
+        }
+        | Instruction::Bne {
+            i: Value::Label(label),
+            ..
+        }) = &line.instruction
+        else {
+            continue;
+        };
+        // Undefined labels are reported by replace_labels
+        let Some(target) = labels.get(label.as_str()) else {
+            continue;
+        };
+        let offset = (*target as i64 - (line.address as i64 + 4)) / 4;
+        if !in_range(offset) {
+            result.push(idx);
+        }
+    }
+    result
+}
+
+// Rewrites `beq $s, $t, far` into
+//
+//   bne $s, $t, 3
+//   lis $scratch
+//   .word far
+//   jr $scratch
+//
+// and likewise bne into beq. The new lines keep the original's source location.
+fn expand(line: &Line, scratch: u8) -> Vec<Line> {
+    let (inverted, label) = match &line.instruction {
+        Instruction::Beq {
+            s,
+            t,
+            i: Value::Label(label),
+        } => (
+            Instruction::Bne {
+                s: *s,
+                t: *t,
+                i: Value::Literal(3),
+            },
+            label,
+        ),
+        Instruction::Bne {
+            s,
+            t,
+            i: Value::Label(label),
+        } => (
+            Instruction::Beq {
+                s: *s,
+                t: *t,
+                i: Value::Literal(3),
+            },
+            label,
+        ),
+        _ => unreachable!(),
+    };
+    let instructions = [
+        inverted,
+        Instruction::Lis { d: scratch },
+        Instruction::Word {
+            i: Value::Label(label.clone()),
+        },
+        Instruction::Jr { s: scratch },
+    ];
+    instructions
+        .into_iter()
+        .enumerate()
+        .map(|(idx, instruction)| Line {
+            text: line.text.clone(),
+            file: line.file,
+            line_number: line.line_number,
+            labels: if idx == 0 {
+                line.labels.clone()
+            } else {
+                Vec::new()
+            },
+            instruction,
+            section: line.section,
+            ..Line::default()
+        })
+        .collect()
+}
+
+// Lays out the program, replacing branches that can't reach their target
+// until no more are out of range. Growing the code can push other branches out
+// of range, so this repeats the layout after every round of rewrites.
+pub fn layout(
+    lines: &mut Vec<Line>,
+    bases: SectionBases,
+    source_names: &[&str],
+    scratch: Option<u8>,
+) -> Vec<SectionRange> {
+    loop {
+        let ranges = sections::layout(lines, bases, source_names);
+        let far = far_branches(lines);
+        if far.is_empty() {
+            return ranges;
+        }
+        let Some(scratch) = scratch else {
+            let locations: Vec<String> = far
+                .iter()
+                .map(|idx| {
+                    format!(
+                        "{}:{}",
+                        source_names[lines[*idx].file], lines[*idx].line_number
+                    )
+                })
+                .collect();
+            panic!(
+                "Branch target out of range at {}; pass --scratch-register to relax far branches",
+                locations.join(", ")
+            );
+        };
+        for idx in far.into_iter().rev() {
+            let expanded = expand(&lines[idx], scratch);
+            lines.splice(idx..=idx, expanded);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Region {
+    Code,
+    Data,
+    Stack,
+    Heap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Violation {
+    CodeWrite,
+    UninitializedStackRead,
+    StackOverflow,
+}
+
+struct Frame {
+    entry_step: u64,
+    return_address: u32,
+}
+
+// Shadow memory for the emulator: remembers when each word was last written
+// and which region it belongs to, and reports suspicious accesses with their
+// source location.
+pub struct Sanitizer {
+    symbols: SymbolTable,
+    locations: HashMap<u32, String>,
+    code: HashSet<u32>,
+    program_end: u32,
+    stack_top: u32,
+    stack_limit: u32,
+    // The live $30; everything below it is unallocated
+    stack_pointer: u32,
+    step: u64,
+    last_write: HashMap<u32, u64>,
+    frames: Vec<Frame>,
+    reported: HashSet<(Violation, u32)>,
+    pub errors: u64,
+}
+
+impl Sanitizer {
+    pub fn new(
+        symbols: SymbolTable,
+        locations: HashMap<u32, String>,
+        code: HashSet<u32>,
+        program_end: u32,
+        stack_top: u32,
+        stack_size: u32,
+    ) -> Sanitizer {
+        Sanitizer {
+            symbols,
+            locations,
+            code,
+            program_end,
+            stack_top,
+            stack_limit: stack_top.saturating_sub(stack_size),
+            stack_pointer: stack_top,
+            step: 0,
+            last_write: HashMap::new(),
+            frames: vec![Frame {
+                entry_step: 0,
+                return_address: 0,
+            }],
+            reported: HashSet::new(),
+            errors: 0,
+        }
+    }
+
+    fn region(&self, addr: u32) -> Region {
+        if addr < self.program_end {
+            if self.code.contains(&(addr / 4)) {
+                Region::Code
+            } else {
+                Region::Data
+            }
+        } else if self.stack_limit <= addr && addr < self.stack_top {
+            Region::Stack
+        } else {
+            Region::Heap
+        }
+    }
+
+    fn location(&self, pc: u32) -> String {
+        match self.locations.get(&pc) {
+            Some(location) => format!("{location} ({})", self.symbols.describe(pc)),
+            None => self.symbols.describe(pc),
+        }
+    }
+
+    // Each kind of problem is only reported once per instruction
+    fn report(&mut self, violation: Violation, pc: u32, message: String) {
+        self.errors += 1;
+        if self.reported.insert((violation, pc)) {
+            eprintln!("sanitizer: {message} at {}", self.location(pc));
+        }
+    }
+
+    pub fn record_instruction(&mut self) {
+        self.step += 1;
+    }
+
+    pub fn record_call(&mut self, return_address: u32) {
+        self.frames.push(Frame {
+            entry_step: self.step,
+            return_address,
+        });
+    }
+
+    pub fn record_jump(&mut self, target: u32) {
+        if let Some(depth) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == target)
+        {
+            self.frames.truncate(depth.max(1));
+        }
+    }
+
+    // Called on every write to $30
+    pub fn record_stack_pointer(&mut self, stack_pointer: u32, pc: u32) {
+        self.stack_pointer = stack_pointer;
+        if stack_pointer < self.stack_limit {
+            let message = format!(
+                "stack overflow, $30 = 0x{stack_pointer:08x} is below the limit 0x{:08x}",
+                self.stack_limit
+            );
+            self.report(Violation::StackOverflow, pc, message);
+        }
+    }
+
+    pub fn check_read(&mut self, addr: u32, pc: u32) {
+        if self.region(addr) != Region::Stack {
+            return;
+        }
+        let frame = self.frames.last().unwrap();
+        let written = self.last_write.get(&(addr / 4)).copied();
+        if addr < self.stack_pointer && written.is_none_or(|step| step < frame.entry_step) {
+            let message = format!("read of uninitialized stack slot 0x{addr:08x}");
+            self.report(Violation::UninitializedStackRead, pc, message);
+        }
+    }
+
+    pub fn check_write(&mut self, addr: u32, pc: u32) {
+        if self.region(addr) == Region::Code {
+            let message = format!(
+                "write to code at 0x{addr:08x} ({})",
+                self.symbols.describe(addr)
+            );
+            self.report(Violation::CodeWrite, pc, message);
+        }
+        self.last_write.insert(addr / 4, self.step);
+    }
+}
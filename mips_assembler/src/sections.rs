@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Instruction, Line};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    #[default]
+    Text,
+    Data,
+    Bss,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Text => write!(f, ".text"),
+            Section::Data => write!(f, ".data"),
+            Section::Bss => write!(f, ".bss"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Directive {
+    Section(Section),
+    // Moves the location counter of the current section to an absolute address
+    Org(u32),
+    // Reserves a number of zeroed bytes
+    Space(u32),
+}
+
+fn parse_address(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+impl Directive {
+    pub fn parse(tokens: &[&str], line_number: usize) -> Option<Directive> {
+        let operand = |name: &str| {
+            let value = tokens
+                .get(1)
+                .and_then(|value| parse_address(value))
+                .unwrap_or_else(|| panic!("Expected {name} <bytes> on line {line_number}"));
+            if !value.is_multiple_of(4) {
+                panic!("{name} {value} on line {line_number} is not a multiple of 4");
+            }
+            value
+        };
+        match *tokens.first()? {
+            ".text" => Some(Directive::Section(Section::Text)),
+            ".data" => Some(Directive::Section(Section::Data)),
+            ".bss" => Some(Directive::Section(Section::Bss)),
+            ".org" => Some(Directive::Org(operand(".org"))),
+            ".space" => Some(Directive::Space(operand(".space"))),
+            _ => None,
+        }
+    }
+}
+
+// Fixed start addresses; sections without one follow the previous section
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SectionBases {
+    pub text: u32,
+    pub data: Option<u32>,
+    pub bss: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SectionRange {
+    pub section: Section,
+    pub start: u32,
+    pub end: u32,
+}
+
+// The assembled words of one section, from its start address. Gaps left by
+// .org inside the section are zeros.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub start: u32,
+    pub words: Vec<u32>,
+}
+
+fn size(line: &Line) -> u32 {
+    match line.directive {
+        Some(Directive::Space(bytes)) => bytes,
+        _ if line.instruction != Instruction::Noop => 4,
+        _ => 0,
+    }
+}
+
+// Assigns a section and an address to every line. Each file starts out in
+// .text, and the sections are laid out in the order .text, .data, .bss.
+pub fn layout(lines: &mut [Line], bases: SectionBases, source_names: &[&str]) -> Vec<SectionRange> {
+    let mut current = Section::Text;
+    let mut file = None;
+    for line in lines.iter_mut() {
+        if file != Some(line.file) {
+            current = Section::Text;
+            file = Some(line.file);
+        }
+        if let Some(Directive::Section(section)) = line.directive {
+            current = section;
+        }
+        line.section = current;
+        if current == Section::Bss && line.instruction != Instruction::Noop {
+            panic!(
+                "{}:{}: only labels and .space are allowed in .bss",
+                source_names[line.file], line.line_number
+            );
+        }
+    }
+
+    let mut ranges = Vec::new();
+    let mut owners: HashMap<u32, (usize, usize)> = HashMap::new();
+    let mut next_base = 0;
+    for (section, base) in [
+        (Section::Text, Some(bases.text)),
+        (Section::Data, bases.data),
+        (Section::Bss, bases.bss),
+    ] {
+        let base = base.unwrap_or(next_base);
+        let (mut addr, mut start, mut end) = (base, base, base);
+        for line in lines.iter_mut().filter(|line| line.section == section) {
+            if let Some(Directive::Org(org)) = line.directive {
+                addr = org;
+                start = start.min(org);
+            }
+            line.address = addr;
+            addr += size(line);
+            end = end.max(addr);
+            for word in (line.address..addr).step_by(4) {
+                if let Some((file, line_number)) =
+                    owners.insert(word, (line.file, line.line_number))
+                {
+                    panic!(
+                        "{}:{} and {}:{} both place a word at 0x{word:08x}",
+                        source_names[file], line_number, source_names[line.file], line.line_number
+                    );
+                }
+            }
+        }
+        ranges.push(SectionRange {
+            section,
+            start,
+            end,
+        });
+        next_base = next_base.max(end);
+    }
+    ranges
+}
@@ -0,0 +1,151 @@
+use std::fs;
+
+use crate::MipsEmulator;
+
+// Snapshot files are a sequence of big-endian words after the magic number:
+//
+//   version, 32 registers, hi, lo, pc, delay slot target (flag, value),
+//   heap break, exit status (flag, value), instructions executed,
+//   number of memory words, then (word address, value) pairs,
+//   number of devices, then per device: start address, name length, name
+//   bytes, state length, state bytes (all padded to whole words)
+const MAGIC: &[u8; 8] = b"MIPSSNAP";
+const VERSION: u32 = 1;
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn word(&mut self, word: u32) {
+        self.bytes.extend_from_slice(&word.to_be_bytes());
+    }
+
+    fn optional(&mut self, value: Option<u32>) {
+        self.word(value.is_some() as u32);
+        self.word(value.unwrap_or(0));
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.word(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+        while !self.bytes.len().is_multiple_of(4) {
+            self.bytes.push(0);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn word(&mut self) -> Result<u32, String> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + 4)
+            .ok_or("Snapshot file is truncated")?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn optional(&mut self) -> Result<Option<u32>, String> {
+        let present = self.word()? != 0;
+        let value = self.word()?;
+        Ok(if present { Some(value) } else { None })
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.word()? as usize;
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or("Snapshot file is truncated")?
+            .to_vec();
+        self.offset += len.div_ceil(4) * 4;
+        Ok(bytes)
+    }
+}
+
+impl MipsEmulator {
+    pub(crate) fn save_snapshot(&self, path: &str) -> Result<(), String> {
+        let mut writer = Writer {
+            bytes: MAGIC.to_vec(),
+        };
+        writer.word(VERSION);
+        for register in self.registers {
+            writer.word(register);
+        }
+        writer.word(self.hi);
+        writer.word(self.lo);
+        writer.word(self.pc);
+        writer.optional(self.delay_slot_target);
+        writer.word(self.heap_break);
+        writer.optional(self.exit_status);
+        writer.word((self.steps >> 32) as u32);
+        writer.word(self.steps as u32);
+
+        let mut memory: Vec<(&u32, &u32)> = self.memory.iter().collect();
+        memory.sort();
+        writer.word(memory.len() as u32);
+        for (addr, word) in memory {
+            writer.word(*addr);
+            writer.word(*word);
+        }
+
+        let devices = self.devices.save_state();
+        writer.word(devices.len() as u32);
+        for (start, name, state) in devices {
+            writer.word(start);
+            writer.bytes(name.as_bytes());
+            writer.bytes(&state);
+        }
+        fs::write(path, writer.bytes).map_err(|err| format!("Could not write {path}: {err}"))
+    }
+
+    pub(crate) fn restore_snapshot(&mut self, path: &str) -> Result<(), String> {
+        let bytes = fs::read(path).map_err(|err| format!("Could not read {path}: {err}"))?;
+        if !bytes.starts_with(MAGIC) {
+            return Err(format!("{path} is not a snapshot file"));
+        }
+        let mut reader = Reader {
+            bytes: &bytes,
+            offset: MAGIC.len(),
+        };
+        let version = reader.word()?;
+        if version != VERSION {
+            return Err(format!(
+                "{path} is a version {version} snapshot, expected version {VERSION}"
+            ));
+        }
+        for register in self.registers.iter_mut() {
+            *register = reader.word()?;
+        }
+        self.hi = reader.word()?;
+        self.lo = reader.word()?;
+        self.pc = reader.word()?;
+        self.delay_slot_target = reader.optional()?;
+        self.heap_break = reader.word()?;
+        self.exit_status = reader.optional()?;
+        self.steps = ((reader.word()? as u64) << 32) | reader.word()? as u64;
+
+        self.memory.clear();
+        self.blocks.clear();
+        for _ in 0..reader.word()? {
+            let addr = reader.word()?;
+            let word = reader.word()?;
+            self.memory.insert(addr, word);
+        }
+
+        for _ in 0..reader.word()? {
+            let start = reader.word()?;
+            let name = String::from_utf8_lossy(&reader.bytes()?).to_string();
+            let state = reader.bytes()?;
+            if !self.devices.restore_state(start, &name, &state) {
+                eprintln!("Snapshot device {name} at 0x{start:08x} is not attached, skipping");
+            }
+        }
+        Ok(())
+    }
+}
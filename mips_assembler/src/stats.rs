@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Default)]
+pub struct ExecutionStats {
+    pub instructions: u64,
+    pub per_opcode: BTreeMap<&'static str, u64>,
+    pub loads: u64,
+    pub stores: u64,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    pub mmio_reads: u64,
+    pub mmio_writes: u64,
+}
+
+impl ExecutionStats {
+    pub fn record_instruction(&mut self, mnemonic: &'static str) {
+        self.instructions += 1;
+        *self.per_opcode.entry(mnemonic).or_insert(0) += 1;
+    }
+
+    pub fn record_branch(&mut self, taken: bool) {
+        if taken {
+            self.branches_taken += 1;
+        } else {
+            self.branches_not_taken += 1;
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut result = String::new();
+        writeln!(result, "Executed {} instructions", self.instructions).unwrap();
+        for (mnemonic, count) in &self.per_opcode {
+            let percent = 100.0 * *count as f64 / self.instructions as f64;
+            writeln!(result, "  {mnemonic:<6} {count:>12} ({percent:5.1}%)").unwrap();
+        }
+        writeln!(result, "Loads:  {:>12}", self.loads).unwrap();
+        writeln!(result, "Stores: {:>12}", self.stores).unwrap();
+        writeln!(
+            result,
+            "Branches: {} taken, {} not taken",
+            self.branches_taken, self.branches_not_taken
+        )
+        .unwrap();
+        writeln!(
+            result,
+            "MMIO: {} reads, {} writes",
+            self.mmio_reads, self.mmio_writes
+        )
+        .unwrap();
+        result
+    }
+
+    pub fn to_json(&self) -> String {
+        let per_opcode: Vec<String> = self
+            .per_opcode
+            .iter()
+            .map(|(mnemonic, count)| format!("\"{mnemonic}\": {count}"))
+            .collect();
+        let mut result = String::new();
+        writeln!(result, "{{").unwrap();
+        writeln!(result, "  \"instructions\": {},", self.instructions).unwrap();
+        writeln!(result, "  \"per_opcode\": {{{}}},", per_opcode.join(", ")).unwrap();
+        writeln!(result, "  \"loads\": {},", self.loads).unwrap();
+        writeln!(result, "  \"stores\": {},", self.stores).unwrap();
+        writeln!(result, "  \"branches_taken\": {},", self.branches_taken).unwrap();
+        writeln!(
+            result,
+            "  \"branches_not_taken\": {},",
+            self.branches_not_taken
+        )
+        .unwrap();
+        writeln!(result, "  \"mmio_reads\": {},", self.mmio_reads).unwrap();
+        writeln!(result, "  \"mmio_writes\": {}", self.mmio_writes).unwrap();
+        writeln!(result, "}}").unwrap();
+        result
+    }
+}
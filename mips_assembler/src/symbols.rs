@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use crate::labels::is_numeric_local;
+use crate::Line;
+
+// Label addresses sorted by address, used to map program counters back to
+// the label they fall under. When several labels share an address, the one
+// that appears first in the source wins.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<(u32, String)>,
+    addresses: HashMap<String, u32>,
+}
+
+impl SymbolTable {
+    pub fn new(lines: &[Line], label_locations: &HashMap<&str, u32>) -> SymbolTable {
+        let mut by_address: HashMap<u32, String> = HashMap::new();
+        for line in lines {
+            for label in &line.labels {
+                let label = &label[..label.len() - 1];
+                // Numeric labels have no useful name, so report the enclosing one
+                if is_numeric_local(label) {
+                    continue;
+                }
+                if let Some(addr) = label_locations.get(label) {
+                    by_address.entry(*addr).or_insert_with(|| label.to_string());
+                }
+            }
+        }
+        let mut symbols: Vec<(u32, String)> = by_address.into_iter().collect();
+        symbols.sort_by_key(|(addr, _)| *addr);
+        let addresses = label_locations
+            .iter()
+            .map(|(label, addr)| (label.to_string(), *addr))
+            .collect();
+        SymbolTable { symbols, addresses }
+    }
+
+    // The label defined exactly at `addr`, if any
+    pub fn name_at(&self, addr: u32) -> Option<&str> {
+        self.symbols
+            .binary_search_by_key(&addr, |(addr, _)| *addr)
+            .ok()
+            .map(|idx| self.symbols[idx].1.as_str())
+    }
+
+    pub fn address_of(&self, label: &str) -> Option<u32> {
+        self.addresses.get(label).copied()
+    }
+
+    // The closest label at or before `addr`, with its address
+    pub fn enclosing(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.symbols.partition_point(|(other, _)| *other <= addr);
+        if idx == 0 {
+            return None;
+        }
+        let (start, ref name) = self.symbols[idx - 1];
+        Some((name.as_str(), start))
+    }
+
+    // Formats `addr` as `label+offset`, falling back to the raw address
+    pub fn describe(&self, addr: u32) -> String {
+        match self.enclosing(addr) {
+            Some((name, start)) if start == addr => name.to_string(),
+            Some((name, start)) => format!("{name}+0x{:x}", addr - start),
+            None => format!("0x{addr:08x}"),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::mmio::{EOF, STDIN_ADDRESS, STDOUT_ADDRESS};
+use crate::MipsEmulator;
+
+// Service numbers follow SPIM and MARS: the service goes in $2 ($v0), the
+// argument in $4 ($a0), and results come back in $2.
+const PRINT_INT: u32 = 1;
+const PRINT_STRING: u32 = 4;
+const READ_INT: u32 = 5;
+const SBRK: u32 = 9;
+const EXIT: u32 = 10;
+const PRINT_CHAR: u32 = 11;
+const READ_CHAR: u32 = 12;
+const EXIT2: u32 = 17;
+
+impl MipsEmulator {
+    pub(crate) fn syscall(&mut self) {
+        let service = self.registers[2];
+        let argument = self.registers[4];
+        match service {
+            PRINT_INT => self.console_write(format!("{}", argument as i32).as_bytes()),
+            PRINT_STRING => {
+                let mut addr = argument;
+                loop {
+                    let byte = self.read_byte(addr);
+                    if byte == 0 {
+                        break;
+                    }
+                    self.console_write(&[byte]);
+                    addr = addr.wrapping_add(1);
+                }
+            }
+            READ_INT => {
+                let mut line = Vec::new();
+                loop {
+                    let byte = self.console_read();
+                    if byte == EOF || byte == b'\n' as u32 {
+                        break;
+                    }
+                    line.push(byte as u8);
+                }
+                let line = String::from_utf8_lossy(&line);
+                self.registers[2] = line.trim().parse::<i32>().unwrap_or(0) as u32;
+            }
+            SBRK => {
+                let old_break = self.heap_break;
+                let new_break = old_break.wrapping_add(argument.wrapping_add(3) & !3);
+                // Fresh heap memory reads as zero
+                for addr in (old_break..new_break).step_by(4) {
+                    self.memory.entry(addr / 4).or_insert(0);
+                }
+                self.heap_break = new_break;
+                self.registers[2] = old_break;
+            }
+            EXIT => self.exit_status = Some(0),
+            PRINT_CHAR => self.console_write(&[argument as u8]),
+            READ_CHAR => self.registers[2] = self.console_read(),
+            EXIT2 => self.exit_status = Some(argument),
+            _ => self.crash(format!(
+                "Unknown syscall service {service} at address {}",
+                self.pc - 4
+            )),
+        }
+    }
+
+    fn console_write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.devices.write(STDOUT_ADDRESS, *byte as u32);
+        }
+    }
+
+    fn console_read(&mut self) -> u32 {
+        self.devices.read(STDIN_ADDRESS).unwrap_or(EOF)
+    }
+
+    // Words are stored big-endian, so byte 0 is the most significant
+    fn read_byte(&mut self, addr: u32) -> u8 {
+        let word = self.read(addr & !3);
+        (word >> (8 * (3 - (addr & 3)))) as u8
+    }
+}